@@ -5,23 +5,229 @@
 
 use core::{ops, slice, str};
 
+/// The value of [`BootInformation::magic`].
+///
+/// This is `UEFIBOOT` in ASCII, read as a little-endian integer.
+pub const BOOT_INFORMATION_MAGIC: u64 = u64::from_le_bytes(*b"UEFIBOOT");
+
 #[derive(Debug)]
 #[repr(C)]
 pub struct BootInformation {
+    /// Always [`BOOT_INFORMATION_MAGIC`].
+    pub magic: u64,
+    /// A checksum of the other fields, computed after they are populated.
+    ///
+    /// See [`compute_checksum`](Self::compute_checksum) for the algorithm.
+    pub checksum: u32,
     pub size: usize,
     pub frame_buffer: Option<FrameBuffer>,
     pub rsdp_address: Option<usize>,
+    /// The physical address of the flattened device tree provided by the
+    /// firmware, if any.
+    ///
+    /// The device tree is copied into
+    /// [`Bootloader`](MemoryRegionKind::Bootloader) memory, as the
+    /// firmware's copy may be in memory reported as usable.
+    pub device_tree_address: Option<usize>,
+    /// The physical address of the top-level page table, which is loaded when
+    /// the kernel is entered.
+    pub page_table_address: usize,
+    /// Whether UEFI Secure Boot was enabled, or `None` if the firmware doesn't
+    /// support Secure Boot.
+    pub secure_boot: Option<bool>,
+    /// The approximate TSC frequency in hertz, or `None` if it couldn't be
+    /// measured.
+    ///
+    /// The frequency is calibrated against the UEFI timer over a short
+    /// interval, so it is only a rough estimate that kernels should refine.
+    pub tsc_frequency: Option<u64>,
+    /// The physical memory occupied by the bootloader's image, or `None` if it
+    /// couldn't be determined.
+    ///
+    /// The bootloader's code is still resident when the kernel is entered, so
+    /// the kernel shouldn't reuse this memory until it no longer needs
+    /// anything the bootloader set up (e.g. the context switch function). The
+    /// image is reported as
+    /// [`BootloaderReclaimable`](MemoryRegionKind::BootloaderReclaimable)
+    /// memory.
+    pub bootloader_image: Option<MemoryRegion>,
+    /// The kernel's first executable segment, if the bootloader was configured
+    /// to additionally identity-map it.
+    ///
+    /// The identity mapping lets an entry stub run at its physical address
+    /// before jumping to its virtual address. The kernel should unmap it once
+    /// it's running in the higher half.
+    pub kernel_identity_map: Option<KernelIdentityMap>,
+    /// The UEFI firmware vendor, converted from UCS-2 to UTF-8.
+    ///
+    /// Long vendor strings may be truncated.
+    pub firmware_vendor: &'static str,
+    /// The vendor-specific UEFI firmware revision.
+    pub firmware_revision: u32,
+    /// The `env.<key> = <value>` pairs from the bootloader configuration.
+    pub config: BootConfig,
     pub memory_regions: MemoryRegions,
     pub modules: Modules,
+    /// The kernel's ELF sections.
+    ///
+    /// The kernel is always loaded at its linked addresses, including
+    /// position-independent (`ET_DYN`) kernels, so no load bias is applied and
+    /// the section addresses can be used for symbolization as is.
     pub elf_sections: ElfSections,
 }
 
+impl BootInformation {
+    /// Computes the checksum of the boot information.
+    ///
+    /// The checksum is the 32-bit FNV-1a hash of every field except
+    /// [`magic`](Self::magic) and [`checksum`](Self::checksum), in declaration
+    /// order. Fields are hashed by value rather than as raw memory, so padding
+    /// isn't included:
+    /// - Integers are hashed as their little-endian bytes, with `usize`s taking
+    ///   their native width.
+    /// - `bool`s are hashed as a single `0` or `1` byte.
+    /// - `Option`s are hashed as a `0` byte for `None`, or a `1` byte followed
+    ///   by the value for `Some`.
+    /// - Structs are hashed as their fields, in declaration order.
+    /// - Enums are hashed as the index of the variant in declaration order, as
+    ///   a `u32`, followed by the variant's fields.
+    /// - Slices and [`firmware_vendor`](Self::firmware_vendor) are hashed as
+    ///   their address followed by their length, so the memory they point to
+    ///   isn't covered.
+    #[must_use]
+    pub fn compute_checksum(&self) -> u32 {
+        let mut hasher = Fnv1a::new();
+
+        hasher.usize(self.size);
+        hasher.option(self.frame_buffer.as_ref(), |hasher, frame_buffer| {
+            hasher.usize(frame_buffer.physical);
+            hasher.usize(frame_buffer.virt);
+            let info = &frame_buffer.info;
+            hasher.usize(info.size);
+            hasher.usize(info.width);
+            hasher.usize(info.height);
+            hasher.u32(match info.pixel_format {
+                PixelFormat::Rgb => 0,
+                PixelFormat::Bgr => 1,
+            });
+            hasher.usize(info.bytes_per_pixel);
+            hasher.usize(info.stride);
+            hasher.usize(info.pitch_bytes);
+        });
+        hasher.option(self.rsdp_address.as_ref(), |hasher, address| {
+            hasher.usize(*address);
+        });
+        hasher.option(self.device_tree_address.as_ref(), |hasher, address| {
+            hasher.usize(*address);
+        });
+        hasher.usize(self.page_table_address);
+        hasher.option(self.secure_boot.as_ref(), |hasher, secure_boot| {
+            hasher.bytes(&[u8::from(*secure_boot)]);
+        });
+        hasher.option(self.tsc_frequency.as_ref(), |hasher, frequency| {
+            hasher.bytes(&frequency.to_le_bytes());
+        });
+        hasher.option(self.bootloader_image.as_ref(), Fnv1a::memory_region);
+        hasher.option(self.kernel_identity_map.as_ref(), |hasher, map| {
+            hasher.usize(map.physical);
+            hasher.usize(map.virt);
+            hasher.usize(map.len);
+        });
+        hasher.slice(self.firmware_vendor.as_ptr(), self.firmware_vendor.len());
+        hasher.u32(self.firmware_revision);
+        hasher.slice(self.config.ptr, self.config.len);
+        hasher.slice(self.memory_regions.ptr, self.memory_regions.len);
+        hasher.slice(self.modules.ptr, self.modules.len);
+        hasher.slice(self.elf_sections.ptr, self.elf_sections.len);
+
+        hasher.hash
+    }
+
+    /// Returns whether the magic value and checksum are correct.
+    ///
+    /// Kernels should check this on entry to detect being passed an incorrect
+    /// pointer or corrupted boot information.
+    #[must_use]
+    pub fn is_valid(&self) -> bool {
+        self.magic == BOOT_INFORMATION_MAGIC && self.checksum == self.compute_checksum()
+    }
+}
+
+/// The hasher used by [`BootInformation::compute_checksum`].
+struct Fnv1a {
+    hash: u32,
+}
+
+impl Fnv1a {
+    const OFFSET_BASIS: u32 = 0x811c_9dc5;
+    const PRIME: u32 = 0x0100_0193;
+
+    fn new() -> Self {
+        Self {
+            hash: Self::OFFSET_BASIS,
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.hash ^= u32::from(*byte);
+            self.hash = self.hash.wrapping_mul(Self::PRIME);
+        }
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn usize(&mut self, value: usize) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn option<T>(&mut self, value: Option<&T>, f: impl FnOnce(&mut Self, &T)) {
+        match value {
+            Some(value) => {
+                self.bytes(&[1]);
+                f(self, value);
+            }
+            None => self.bytes(&[0]),
+        }
+    }
+
+    fn slice<T>(&mut self, ptr: *const T, len: usize) {
+        self.usize(ptr as usize);
+        self.usize(len);
+    }
+
+    fn memory_region(&mut self, region: &MemoryRegion) {
+        self.usize(region.start);
+        self.usize(region.len);
+        let (index, tag) = match region.kind {
+            MemoryRegionKind::Usable => (0, None),
+            MemoryRegionKind::Bootloader => (1, None),
+            MemoryRegionKind::BootloaderReclaimable => (2, None),
+            MemoryRegionKind::AcpiReclaimable => (3, None),
+            MemoryRegionKind::AcpiNvs => (4, None),
+            MemoryRegionKind::PersistentMemory => (5, None),
+            MemoryRegionKind::UnknownUefi(tag) => (6, Some(tag)),
+        };
+        self.u32(index);
+        if let Some(tag) = tag {
+            self.u32(tag);
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct FrameBuffer {
     /// The framebuffer's physical address.
     pub physical: usize,
     /// The framebuffer's virtual address.
+    ///
+    /// This is zero if the bootloader was configured not to map the
+    /// framebuffer, in which case the kernel must map [`physical`] itself.
+    ///
+    /// [`physical`]: Self::physical
     pub virt: usize,
     pub info: FrameBufferInfo,
 }
@@ -29,12 +235,27 @@ pub struct FrameBuffer {
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct FrameBufferInfo {
+    /// The size of the framebuffer in bytes.
+    ///
+    /// This is at least `pitch_bytes * height`, and is the length of the
+    /// framebuffer's mapping.
     pub size: usize,
     pub width: usize,
     pub height: usize,
     pub pixel_format: PixelFormat,
     pub bytes_per_pixel: usize,
+    /// The number of pixels between the start of a line and the start of the
+    /// next.
+    ///
+    /// This may be larger than [`width`](Self::width) if lines are padded.
     pub stride: usize,
+    /// The number of bytes between the start of a line and the start of the
+    /// next.
+    ///
+    /// This is `stride * bytes_per_pixel` for framebuffers provided by the
+    /// bootloader, but kernels should use it rather than computing the pitch
+    /// themselves.
+    pub pitch_bytes: usize,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -53,6 +274,34 @@ pub struct MemoryRegions {
     pub(crate) len: usize,
 }
 
+impl MemoryRegions {
+    /// Returns an iterator over the [`Usable`][MemoryRegionKind::Usable]
+    /// regions.
+    pub fn usable_regions(&self) -> impl Iterator<Item = &MemoryRegion> {
+        self.iter()
+            .filter(|region| region.kind == MemoryRegionKind::Usable)
+    }
+
+    /// Returns an iterator over the regions that aren't
+    /// [`Usable`][MemoryRegionKind::Usable].
+    ///
+    /// Some of these regions, such as
+    /// [`AcpiReclaimable`][MemoryRegionKind::AcpiReclaimable] and
+    /// [`BootloaderReclaimable`][MemoryRegionKind::BootloaderReclaimable]
+    /// regions, can be used once the kernel no longer needs their contents.
+    pub fn reserved_regions(&self) -> impl Iterator<Item = &MemoryRegion> {
+        self.iter()
+            .filter(|region| region.kind != MemoryRegionKind::Usable)
+    }
+
+    /// Returns the largest [`Usable`][MemoryRegionKind::Usable] region, if
+    /// any.
+    #[must_use]
+    pub fn largest_usable_region(&self) -> Option<&MemoryRegion> {
+        self.usable_regions().max_by_key(|region| region.len)
+    }
+}
+
 impl ops::Deref for MemoryRegions {
     type Target = [MemoryRegion];
 
@@ -85,6 +334,18 @@ impl From<MemoryRegions> for &'static mut [MemoryRegion] {
     }
 }
 
+/// A kernel segment that is mapped at both its physical and virtual address.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(C)]
+pub struct KernelIdentityMap {
+    /// The physical address of the segment, which is also where it's
+    /// identity-mapped.
+    pub physical: usize,
+    /// The virtual address the segment was linked at.
+    pub virt: usize,
+    pub len: usize,
+}
+
 /// Represent a physical memory region.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 #[repr(C)]
@@ -123,6 +384,21 @@ pub enum MemoryRegionKind {
     ///
     /// This memory should _not_ be used by the kernel.
     Bootloader,
+    /// Memory used by the bootloader that the kernel doesn't need, including
+    /// the bootloader's image, the UEFI memory map, and temporary buffers.
+    ///
+    /// This memory can be used by the kernel once it has finished reading the
+    /// boot info. It never contains the boot info, kernel stack, or page
+    /// tables, which are [`Bootloader`](Self::Bootloader) memory.
+    BootloaderReclaimable,
+    /// Memory containing ACPI tables that can be reclaimed by the kernel once
+    /// it has finished reading them.
+    AcpiReclaimable,
+    /// Memory reserved for use by the firmware that must be preserved across
+    /// ACPI sleep states.
+    AcpiNvs,
+    /// Byte-addressable non-volatile memory.
+    PersistentMemory,
     /// An unknown memory region reported by the UEFI firmware.
     ///
     /// Contains the UEFI memory type tag.
@@ -183,6 +459,11 @@ pub struct Module {
     pub offset: usize,
     /// The length of the module in bytes.
     pub len: usize,
+    /// The alignment of the module's physical start address in bytes.
+    ///
+    /// This is at least the page size, and at least the alignment requested
+    /// in the bootloader configuration.
+    pub align: usize,
 }
 
 impl Module {
@@ -252,9 +533,38 @@ pub struct ElfSection {
     pub size: usize,
     /// The section flags.
     pub flags: u64,
+    /// The required alignment of the section's start address, or zero or one
+    /// if the section has no alignment requirement.
+    pub align: usize,
 }
 
 impl ElfSection {
+    const SHF_WRITE: u64 = 0x1;
+    const SHF_ALLOC: u64 = 0x2;
+    const SHF_EXECINSTR: u64 = 0x4;
+
+    /// Returns whether the section occupies memory when the kernel is running.
+    ///
+    /// Other sections (e.g. debug information) aren't loaded, so their start
+    /// address is meaningless and they should be ignored when mapping the
+    /// kernel.
+    #[must_use]
+    pub const fn is_allocated(&self) -> bool {
+        self.flags & Self::SHF_ALLOC != 0
+    }
+
+    /// Returns whether the section should be writable.
+    #[must_use]
+    pub const fn is_writable(&self) -> bool {
+        self.flags & Self::SHF_WRITE != 0
+    }
+
+    /// Returns whether the section contains executable instructions.
+    #[must_use]
+    pub const fn is_executable(&self) -> bool {
+        self.flags & Self::SHF_EXECINSTR != 0
+    }
+
     /// The name of the section.
     #[must_use]
     pub fn name(&self) -> &str {
@@ -266,3 +576,289 @@ impl ElfSection {
         str::from_utf8(&self.name[..end]).expect("invalid bytes in section name")
     }
 }
+
+/// FFI-safe slice of [`BootConfigEntry`] structs, semantically equivalent to
+/// `&'static mut [BootConfigEntry]`.
+///
+/// Each key appears at most once.
+#[derive(Debug)]
+#[repr(C)]
+pub struct BootConfig {
+    pub(crate) ptr: *mut BootConfigEntry,
+    pub(crate) len: usize,
+}
+
+impl BootConfig {
+    /// Returns the value of the given key, if present.
+    #[must_use]
+    pub fn get(&self, key: &str) -> Option<&'static str> {
+        self.iter()
+            .find(|entry| entry.key == key)
+            .map(|entry| entry.value)
+    }
+}
+
+impl ops::Deref for BootConfig {
+    type Target = [BootConfigEntry];
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: Pointer and length were calculated from a valid slice.
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl ops::DerefMut for BootConfig {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: Pointer and length were calculated from a valid slice.
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl From<&'static mut [BootConfigEntry]> for BootConfig {
+    fn from(entries: &'static mut [BootConfigEntry]) -> Self {
+        Self {
+            ptr: entries.as_mut_ptr(),
+            len: entries.len(),
+        }
+    }
+}
+
+impl From<BootConfig> for &'static mut [BootConfigEntry] {
+    fn from(config: BootConfig) -> Self {
+        // SAFETY: Pointer and length were calculated from a valid slice.
+        unsafe { slice::from_raw_parts_mut(config.ptr, config.len) }
+    }
+}
+
+/// A key/value pair from the bootloader configuration.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct BootConfigEntry {
+    pub key: &'static str,
+    pub value: &'static str,
+}
+
+/// The name of the ELF notes used by the kernel to pass information to the
+/// bootloader.
+///
+/// The kernel includes the notes in a `PT_NOTE` segment, distinguished by
+/// their type: [`KERNEL_STACK_NOTE_TYPE`], [`KERNEL_VERSION_NOTE_TYPE`],
+/// [`KERNEL_MEMORY_NOTE_TYPE`], and [`KERNEL_SEGMENT_HASH_NOTE_TYPE`].
+pub const NOTE_NAME: &str = "uefi-bootloader";
+
+/// The type of the ELF note used by the kernel to request the size and
+/// alignment of its initial stack.
+///
+/// The note's descriptor is a [`KernelStackNote`]. If the note is absent, the
+/// stack size and alignment from the bootloader configuration are used. See
+/// [`NOTE_NAME`].
+pub const KERNEL_STACK_NOTE_TYPE: u32 = 0x100;
+
+/// The descriptor of the ELF note used by the kernel to request an initial
+/// stack.
+///
+/// Both fields are little-endian. The bootloader refuses to boot kernels that
+/// request an empty stack, a stack larger than 64 MiB, or an alignment that
+/// isn't a power of two.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct KernelStackNote {
+    /// The size of the stack in bytes, which is rounded up to the page size.
+    pub size: u64,
+    /// The alignment of the top of the stack in bytes, or zero for the
+    /// default alignment.
+    ///
+    /// Alignments smaller than the page size are rounded up to the page size.
+    pub align: u64,
+}
+
+/// The type of the ELF note used by the kernel to require a bootloader
+/// version.
+///
+/// The note has the name [`NOTE_NAME`] and its descriptor is a
+/// [`KernelVersionNote`]. If the note is absent, any bootloader version is
+/// accepted.
+pub const KERNEL_VERSION_NOTE_TYPE: u32 = 0x101;
+
+/// The descriptor of the ELF note used by the kernel to require a bootloader
+/// version.
+///
+/// All fields are little-endian. The bootloader refuses to boot the kernel
+/// unless its version is semver-compatible with, and no older than, the
+/// required version (i.e. it satisfies `^major.minor.patch`).
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct KernelVersionNote {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+/// The type of the ELF note used by the kernel to request memory with a
+/// custom UEFI memory type.
+///
+/// The note has the name [`NOTE_NAME`] and its descriptor is a
+/// [`KernelMemoryNote`]. The kernel can include multiple notes of this type.
+/// The memory is allocated before exiting boot services, so it's reported in
+/// the memory map as [`UnknownUefi`](MemoryRegionKind::UnknownUefi) regions
+/// with the requested memory type.
+pub const KERNEL_MEMORY_NOTE_TYPE: u32 = 0x102;
+
+/// The descriptor of the ELF note used by the kernel to request memory with a
+/// custom UEFI memory type.
+///
+/// Both fields are little-endian. The bootloader refuses to boot kernels that
+/// request an empty allocation or a memory type outside the OEM and OS
+/// reserved ranges (`0x7000_0000..=0xffff_ffff`), excluding the types the
+/// bootloader uses internally (`0x8000_0000`, `0x8000_0001`, and
+/// `0xffff_ffff`).
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct KernelMemoryNote {
+    /// The UEFI memory type of the allocation.
+    pub memory_type: u64,
+    /// The size of the allocation in bytes, which is rounded up to the page
+    /// size.
+    pub size: u64,
+}
+
+/// The type of the ELF note used by the kernel to provide the hash of a
+/// loadable segment.
+///
+/// The note has the name [`NOTE_NAME`] and its descriptor is a
+/// [`KernelSegmentHashNote`]. The kernel can include one note per segment.
+/// Segments without a note aren't verified.
+pub const KERNEL_SEGMENT_HASH_NOTE_TYPE: u32 = 0x103;
+
+/// The descriptor of the ELF note used by the kernel to provide the hash of a
+/// loadable segment.
+///
+/// Both fields are little-endian. After copying the segment into memory, the
+/// bootloader refuses to boot the kernel if the hash of the segment's file
+/// contents doesn't match.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct KernelSegmentHashNote {
+    /// The index of the segment's program header, which must be less than
+    /// 64.
+    pub index: u32,
+    /// The CRC-32 (IEEE 802.3) of the segment's `p_filesz` bytes of file
+    /// contents.
+    pub crc32: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(start: usize, len: usize, kind: MemoryRegionKind) -> MemoryRegion {
+        MemoryRegion { start, len, kind }
+    }
+
+    fn regions(regions: &mut [MemoryRegion]) -> MemoryRegions {
+        MemoryRegions {
+            ptr: regions.as_mut_ptr(),
+            len: regions.len(),
+        }
+    }
+
+    #[test]
+    fn usable_regions() {
+        let mut raw = [
+            region(0x0, 0x1000, MemoryRegionKind::Bootloader),
+            region(0x1000, 0x2000, MemoryRegionKind::Usable),
+            region(0x3000, 0x1000, MemoryRegionKind::AcpiReclaimable),
+            region(0x4000, 0x4000, MemoryRegionKind::Usable),
+        ];
+        let regions = regions(&mut raw);
+
+        let mut usable = regions.usable_regions();
+        assert_eq!(usable.next(), Some(&raw[1]));
+        assert_eq!(usable.next(), Some(&raw[3]));
+        assert_eq!(usable.next(), None);
+    }
+
+    #[test]
+    fn reserved_regions() {
+        let mut raw = [
+            region(0x0, 0x1000, MemoryRegionKind::Bootloader),
+            region(0x1000, 0x2000, MemoryRegionKind::Usable),
+            region(0x3000, 0x1000, MemoryRegionKind::AcpiReclaimable),
+            region(0x4000, 0x1000, MemoryRegionKind::UnknownUefi(0x8000_0000)),
+        ];
+        let regions = regions(&mut raw);
+
+        let mut reserved = regions.reserved_regions();
+        assert_eq!(reserved.next(), Some(&raw[0]));
+        assert_eq!(reserved.next(), Some(&raw[2]));
+        assert_eq!(reserved.next(), Some(&raw[3]));
+        assert_eq!(reserved.next(), None);
+    }
+
+    #[test]
+    fn largest_usable_region() {
+        let mut raw = [
+            region(0x0, 0x8000, MemoryRegionKind::Bootloader),
+            region(0x8000, 0x2000, MemoryRegionKind::Usable),
+            region(0xa000, 0x4000, MemoryRegionKind::Usable),
+            region(0xe000, 0x1000, MemoryRegionKind::Usable),
+        ];
+        let regions = regions(&mut raw);
+
+        assert_eq!(regions.largest_usable_region(), Some(&raw[2]));
+    }
+
+    #[test]
+    fn checksum_covers_optional_fields() {
+        let mut boot_info = BootInformation {
+            magic: BOOT_INFORMATION_MAGIC,
+            checksum: 0,
+            size: 0,
+            frame_buffer: None,
+            rsdp_address: None,
+            device_tree_address: None,
+            page_table_address: 0,
+            secure_boot: None,
+            tsc_frequency: None,
+            bootloader_image: None,
+            kernel_identity_map: None,
+            firmware_vendor: "",
+            firmware_revision: 0,
+            config: BootConfig {
+                ptr: core::ptr::null_mut(),
+                len: 0,
+            },
+            memory_regions: regions(&mut []),
+            modules: Modules {
+                ptr: core::ptr::null_mut(),
+                len: 0,
+            },
+            elf_sections: ElfSections {
+                ptr: core::ptr::null_mut(),
+                len: 0,
+            },
+        };
+        boot_info.checksum = boot_info.compute_checksum();
+        assert!(boot_info.is_valid());
+
+        // A zero address must be distinguishable from no address.
+        boot_info.rsdp_address = Some(0);
+        assert!(!boot_info.is_valid());
+        boot_info.rsdp_address = None;
+
+        boot_info.secure_boot = Some(false);
+        assert!(!boot_info.is_valid());
+        boot_info.secure_boot = None;
+
+        boot_info.bootloader_image = Some(MemoryRegion::empty());
+        assert!(!boot_info.is_valid());
+    }
+
+    #[test]
+    fn largest_usable_region_without_usable_regions() {
+        let mut raw = [region(0x0, 0x1000, MemoryRegionKind::AcpiNvs)];
+        assert_eq!(regions(&mut raw).largest_usable_region(), None);
+
+        assert_eq!(regions(&mut []).largest_usable_region(), None);
+    }
+}
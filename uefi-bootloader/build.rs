@@ -0,0 +1,40 @@
+use std::{env, fmt::Write, fs, path::PathBuf};
+
+fn main() {
+    if env::var_os("CARGO_FEATURE_EMBEDDED_MODULES").is_none() {
+        return;
+    }
+
+    println!("cargo:rerun-if-env-changed=UEFI_BOOTLOADER_MODULES");
+    let dir = env::var("UEFI_BOOTLOADER_MODULES")
+        .expect("UEFI_BOOTLOADER_MODULES must be set when embedding modules");
+    println!("cargo:rerun-if-changed={dir}");
+
+    let mut paths = fs::read_dir(&dir)
+        .expect("failed to read modules directory")
+        .map(|entry| {
+            entry
+                .expect("failed to read modules directory entry")
+                .path()
+        })
+        .filter(|path| path.is_file())
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    let mut modules = String::from("&[");
+    for path in paths {
+        let path = path
+            .canonicalize()
+            .expect("failed to canonicalize module path");
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .expect("module name is not valid UTF-8");
+        write!(modules, "({name:?}, include_bytes!({path:?})),").expect("failed to write module");
+    }
+    modules.push(']');
+
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR not set"));
+    fs::write(out_dir.join("embedded_modules.rs"), modules)
+        .expect("failed to write embedded modules");
+}
@@ -57,7 +57,7 @@ pub(crate) fn set_up_arch_specific_mappings(context: &mut RuntimeContext) {
     top_level[510].set(top_level_frame, flags);
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub(crate) struct PteFlags(u64);
 
 impl PteFlags {
@@ -114,6 +114,14 @@ impl PteFlags {
             Self(self.0 & !(BITS))
         }
     }
+
+    /// Returns the flags that can be set using [`PteFlags`] methods, ignoring
+    /// those set by the hardware or implicitly by the mapper.
+    pub(crate) fn permissions(self) -> Self {
+        const BITS: u64 = (1 << 0) | (1 << 7) | (1 << 53) | (1 << 54);
+
+        Self(self.0 & BITS)
+    }
 }
 
 impl Page {
@@ -265,6 +273,31 @@ impl Mapper {
 
         barrier::isb(barrier::SY);
     }
+
+    /// Returns the frame that `page` is mapped to and the flags of the
+    /// mapping, or `None` if the page isn't mapped.
+    pub(crate) fn translate(&self, page: Page) -> Option<(Frame, PteFlags)> {
+        let mut table: &PageTable = self.level_zero_page_table;
+        for index in [page.p0_index(), page.p1_index(), page.p2_index()] {
+            let entry = &table[index];
+            if entry.is_unused() {
+                return None;
+            }
+            // SAFETY: The bootloader only creates table descriptors at these levels.
+            table = unsafe { entry.as_page_table() };
+        }
+
+        let entry = &table[page.p3_index()];
+        if entry.is_unused() {
+            None
+        } else {
+            Some((
+                Frame::containing_address(entry.output_address()),
+                // Clear the output address bits.
+                PteFlags(entry.0 & !0x0000_FFFF_FFFF_F000),
+            ))
+        }
+    }
 }
 
 #[derive(Debug)]
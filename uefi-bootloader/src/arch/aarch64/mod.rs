@@ -1,10 +1,11 @@
-use crate::KernelContext;
+use crate::{BootInfo, KernelContext};
 use core::arch::asm;
 use cortex_a::{
     asm::barrier,
     registers::{MAIR_EL1, SCTLR_EL1, TCR_EL1, TTBR0_EL1},
 };
 use tock_registers::interfaces::{ReadWriteable, Writeable};
+use uefi::table::{Boot, SystemTable};
 
 pub(crate) mod memory;
 
@@ -12,6 +13,11 @@ pub(crate) mod memory;
 // when we switch page tables.
 #[allow(clippy::needless_pass_by_value)]
 pub(crate) unsafe fn jump_to_kernel(context: KernelContext) -> ! {
+    let boot_info = match context.boot_info {
+        BootInfo::Native(boot_info) => boot_info,
+        BootInfo::Multiboot2(_) => panic!("multiboot2 handoff is only supported on x86_64"),
+    };
+
     // disable the MMU
     SCTLR_EL1.modify(SCTLR_EL1::M::Disable);
     barrier::isb(barrier::SY);
@@ -30,7 +36,7 @@ pub(crate) unsafe fn jump_to_kernel(context: KernelContext) -> ! {
             in("x3") ASID_ZERO as usize,
             in("x2") context.stack_top.value(),
             in("x1") context.entry_point.value(),
-            in("x0") context.boot_info,
+            in("x0") boot_info,
         )
     }
 
@@ -53,6 +59,27 @@ pub(crate) unsafe fn jump_to_kernel(context: KernelContext) -> ! {
     }
 }
 
+/// Returns `None` as aarch64 doesn't have a TSC.
+pub(crate) fn timestamp() -> Option<u64> {
+    None
+}
+
+/// Returns `None` as aarch64 doesn't have a TSC.
+pub(crate) fn tsc_frequency(_system_table: &SystemTable<Boot>) -> Option<u64> {
+    None
+}
+
+/// Returns an empty list as none of the reported features are specific to
+/// aarch64 yet.
+pub(crate) fn enabled_features() -> [(&'static str, bool); 0] {
+    []
+}
+
+/// Returns `None` as the PCI configuration space isn't accessed on aarch64.
+pub(crate) fn display_controller_bar() -> Option<usize> {
+    None
+}
+
 pub(crate) fn halt() -> ! {
     loop {
         // SAFETY: This instruction will stop the CPU.
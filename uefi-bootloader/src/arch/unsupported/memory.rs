@@ -24,7 +24,7 @@ pub(crate) fn set_up_arch_specific_mappings(_context: &mut RuntimeContext) {
     unimplemented!();
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub(crate) struct PteFlags;
 
 impl PteFlags {
@@ -47,6 +47,10 @@ impl PteFlags {
     pub(crate) fn no_execute(self, _enable: bool) -> Self {
         unimplemented!();
     }
+
+    pub(crate) fn permissions(self) -> Self {
+        unimplemented!();
+    }
 }
 
 pub(crate) struct PageAllocator;
@@ -97,4 +101,8 @@ impl Mapper {
     {
         unimplemented!()
     }
+
+    pub(crate) fn translate(&self, _page: Page) -> Option<(Frame, PteFlags)> {
+        unimplemented!();
+    }
 }
@@ -1,4 +1,5 @@
 use crate::KernelContext;
+use uefi::table::{Boot, SystemTable};
 
 pub(crate) mod memory;
 
@@ -9,6 +10,22 @@ pub(crate) unsafe fn jump_to_kernel(_context: KernelContext) -> ! {
     unimplemented!();
 }
 
+pub(crate) fn timestamp() -> Option<u64> {
+    unimplemented!();
+}
+
+pub(crate) fn tsc_frequency(_system_table: &SystemTable<Boot>) -> Option<u64> {
+    unimplemented!();
+}
+
+pub(crate) fn enabled_features() -> [(&'static str, bool); 0] {
+    unimplemented!();
+}
+
+pub(crate) fn display_controller_bar() -> Option<usize> {
+    unimplemented!();
+}
+
 pub(crate) fn halt() -> ! {
     unimplemented!();
 }
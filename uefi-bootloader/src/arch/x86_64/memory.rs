@@ -6,7 +6,9 @@ use bit_field::BitField;
 use goblin::elf64::program_header::ProgramHeader;
 use x86_64::{
     registers::control::{Cr3, Cr3Flags},
-    structures::paging::{self, OffsetPageTable, PageTable, PageTableIndex},
+    structures::paging::{
+        self, mapper::TranslateResult, OffsetPageTable, PageTable, PageTableIndex, Translate,
+    },
 };
 
 pub(crate) fn is_canonical_virtual_address(virt_addr: usize) -> bool {
@@ -48,7 +50,7 @@ pub(crate) fn set_up_arch_specific_mappings(context: &mut RuntimeContext) {
     );
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub(crate) struct PteFlags(u64);
 
 impl PteFlags {
@@ -85,6 +87,16 @@ impl PteFlags {
             Self(self.0 & !(BITS))
         }
     }
+
+    /// Returns the flags that can be set using [`PteFlags`] methods, ignoring
+    /// those set by the hardware.
+    pub(crate) fn permissions(self) -> Self {
+        const BITS: u64 = paging::PageTableFlags::PRESENT.bits()
+            | paging::PageTableFlags::WRITABLE.bits()
+            | paging::PageTableFlags::NO_EXECUTE.bits();
+
+        Self(self.0 & BITS)
+    }
 }
 
 impl From<PteFlags> for paging::PageTableFlags {
@@ -302,4 +314,23 @@ impl Mapper {
         // TODO: Do we need to flush everytime?
         .flush();
     }
+
+    /// Returns the frame that `page` is mapped to and the flags of the
+    /// mapping, or `None` if the page isn't mapped.
+    pub(crate) fn translate(&self, page: Page) -> Option<(Frame, PteFlags)> {
+        let address = x86_64::VirtAddr::new(page.start_address().value() as u64);
+        match self.inner.translate(address) {
+            TranslateResult::Mapped {
+                frame,
+                offset,
+                flags,
+            } => Some((
+                Frame::containing_address(
+                    PhysicalAddress::from(frame.start_address()) + offset as usize,
+                ),
+                PteFlags(flags.bits()),
+            )),
+            TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) => None,
+        }
+    }
 }
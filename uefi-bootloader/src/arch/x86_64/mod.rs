@@ -1,5 +1,13 @@
-use crate::KernelContext;
-use core::arch::asm;
+use crate::{multiboot2, BootInfo, KernelContext};
+use core::arch::{asm, x86_64::_rdtsc};
+use uefi::table::{Boot, SystemTable};
+use x86_64::{
+    instructions::port::Port,
+    registers::{
+        control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+        model_specific::{Efer, EferFlags, Msr},
+    },
+};
 
 pub(crate) mod memory;
 
@@ -7,18 +15,170 @@ pub(crate) mod memory;
 // when we switch page tables.
 #[allow(clippy::needless_pass_by_value)]
 pub(crate) unsafe fn jump_to_kernel(context: KernelContext) -> ! {
-    // SAFETY: The caller guarantees that the context switch function is
-    // identity-mapped, the stack pointer is mapped in the new page table, and the
-    // kernel entry point is correct.
+    // The System V ABI requires the direction flag to be clear and rsp + 8 to be
+    // 16-byte aligned on function entry, as if the entry point had been called. The
+    // stack is aligned and a null return address is pushed, which also terminates
+    // stack traces in the kernel.
+    match context.boot_info {
+        // SAFETY: The caller guarantees that the context switch function is
+        // identity-mapped, the stack pointer is mapped in the new page table, and the
+        // kernel entry point is correct.
+        BootInfo::Native(boot_info) => unsafe {
+            asm!(
+                "mov cr3, {}; mov rsp, {}; and rsp, -16; push 0; cld; jmp {}",
+                in(reg) context.page_table_frame.start_address().value(),
+                in(reg) context.stack_top.value(),
+                in(reg) context.entry_point.value(),
+                in("rdi") boot_info,
+                options(noreturn),
+            );
+        },
+        // The kernel is entered in long mode like the native handoff, not with the
+        // Multiboot2 specification's machine state. As in the specification, the magic
+        // value is passed in eax and the information address in ebx. They are also
+        // passed in rdi and rsi so that the kernel can use them as arguments to its
+        // entry point. rbx can't be used as an operand as it's reserved by LLVM, so
+        // it's set in the assembly.
+        //
+        // SAFETY: The caller guarantees that the context switch function is
+        // identity-mapped, the stack pointer is mapped in the new page table, and the
+        // kernel entry point is correct.
+        BootInfo::Multiboot2(address) => unsafe {
+            asm!(
+                "mov cr3, {}; mov rsp, {}; and rsp, -16; push 0; cld; mov rbx, rsi; jmp {}",
+                in(reg) context.page_table_frame.start_address().value(),
+                in(reg) context.stack_top.value(),
+                in(reg) context.entry_point.value(),
+                in("rax") multiboot2::BOOTLOADER_MAGIC,
+                in("rdi") multiboot2::BOOTLOADER_MAGIC,
+                in("rsi") address.value(),
+                options(noreturn),
+            );
+        },
+    }
+}
+
+/// Returns the current TSC value.
+pub(crate) fn timestamp() -> Option<u64> {
+    // SAFETY: rdtsc is supported by all x86_64 processors.
+    Some(unsafe { _rdtsc() })
+}
+
+/// Returns the approximate TSC frequency in hertz.
+///
+/// The frequency is measured by counting TSC ticks across a
+/// [`stall`](uefi::table::boot::BootServices::stall), so it is only as accurate
+/// as the firmware's timer.
+pub(crate) fn tsc_frequency(system_table: &SystemTable<Boot>) -> Option<u64> {
+    const STALL_MICROSECONDS: u64 = 10_000;
+
+    // SAFETY: rdtsc is supported by all x86_64 processors.
+    let start = unsafe { _rdtsc() };
+    system_table
+        .boot_services()
+        .stall(STALL_MICROSECONDS as usize);
+    // SAFETY: rdtsc is supported by all x86_64 processors.
+    let end = unsafe { _rdtsc() };
+
+    let ticks = end.checked_sub(start).filter(|ticks| *ticks > 0)?;
+    Some(ticks * (1_000_000 / STALL_MICROSECONDS))
+}
+
+/// Returns the CPU features relevant to the kernel, and whether they are
+/// enabled.
+///
+/// The features are read from the control registers and MSRs, so they reflect
+/// the actual state of the CPU.
+pub(crate) fn enabled_features() -> [(&'static str, bool); 6] {
+    const PAT_MSR: u32 = 0x277;
+    const PAT_WRITE_COMBINING: u64 = 0x01;
+
+    let efer = Efer::read();
+    let cr0 = Cr0::read();
+    let cr4 = Cr4::read();
+    // SAFETY: The PAT is supported by all x86_64 processors.
+    let pat = unsafe { Msr::new(PAT_MSR).read() };
+    let pat_wc = (0..8).any(|i| (pat >> (i * 8)) & 0x7 == PAT_WRITE_COMBINING);
+
+    [
+        ("nxe", efer.contains(EferFlags::NO_EXECUTE_ENABLE)),
+        ("wp", cr0.contains(Cr0Flags::WRITE_PROTECT)),
+        (
+            "smep",
+            cr4.contains(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION),
+        ),
+        (
+            "smap",
+            cr4.contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION),
+        ),
+        ("pge", cr4.contains(Cr4Flags::PAGE_GLOBAL)),
+        ("pat-wc", pat_wc),
+    ]
+}
+
+/// Returns the address of the first prefetchable memory BAR of the first PCI
+/// display controller, which is usually its frame buffer.
+///
+/// The configuration space is accessed using the legacy I/O ports, so only PCI
+/// segment zero is scanned.
+pub(crate) fn display_controller_bar() -> Option<usize> {
+    const CLASS_DISPLAY: u32 = 0x03;
+
+    for bus in 0..=255 {
+        for device in 0..32 {
+            for function in 0..8 {
+                let read = |offset| pci_config_read(bus, device, function, offset);
+
+                if read(0) & 0xffff == 0xffff {
+                    // If function zero doesn't exist, the device doesn't exist.
+                    if function == 0 {
+                        break;
+                    }
+                    continue;
+                }
+                // Only general devices (i.e. header type zero) have six BARs.
+                if read(0x8) >> 24 != CLASS_DISPLAY || (read(0xc) >> 16) & 0x7f != 0 {
+                    continue;
+                }
+
+                let mut index = 0;
+                while index < 6 {
+                    let bar = read(0x10 + index * 4);
+                    // I/O space BAR.
+                    if bar & 0x1 != 0 {
+                        index += 1;
+                        continue;
+                    }
+
+                    let is_64_bit = (bar >> 1) & 0x3 == 0x2;
+                    let mut address = u64::from(bar & !0xf);
+                    if is_64_bit && index < 5 {
+                        address |= u64::from(read(0x10 + (index + 1) * 4)) << 32;
+                    }
+                    if bar & 0x8 != 0 && address != 0 {
+                        return Some(address as usize);
+                    }
+
+                    index += if is_64_bit { 2 } else { 1 };
+                }
+            }
+        }
+    }
+
+    None
+}
+
+fn pci_config_read(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    let address = 0x8000_0000
+        | (u32::from(bus) << 16)
+        | (u32::from(device) << 11)
+        | (u32::from(function) << 8)
+        | u32::from(offset & 0xfc);
+
+    // SAFETY: Reading the PCI configuration space has no side effects.
     unsafe {
-        asm!(
-            "mov cr3, {}; mov rsp, {}; jmp {}",
-            in(reg) context.page_table_frame.start_address().value(),
-            in(reg) context.stack_top.value(),
-            in(reg) context.entry_point.value(),
-            in("rdi") context.boot_info,
-            options(noreturn),
-        );
+        Port::new(0xcf8).write(address);
+        Port::new(0xcfc).read()
     }
 }
 
@@ -1,16 +1,79 @@
 use crate::{
     arch::memory::Mapper,
     context::RuntimeContext,
-    memory::{FrameAllocator, Page, PageRange, PteFlags},
+    memory::{FrameAllocator, Page, PageRange, PteFlags, VirtualAddress, PAGE_SIZE},
+    selftest::Target,
 };
-use core::{alloc::Layout, mem::MaybeUninit, slice};
-use uefi_bootloader_api::{BootInformation, ElfSection, FrameBuffer, MemoryRegion, Module};
+use core::{alloc::Layout, mem, mem::MaybeUninit, slice, str};
+use uefi::table::{Boot, SystemTable};
+use uefi_bootloader_api::{
+    BootConfigEntry, BootInformation, ElfSection, FrameBuffer, MemoryRegion, Module,
+    BOOT_INFORMATION_MAGIC,
+};
+
+/// The maximum length in bytes of the firmware vendor string.
+const MAX_FIRMWARE_VENDOR_LEN: usize = 128;
+
+/// Information about the system that must be gathered before exiting boot
+/// services.
+pub(crate) struct SystemInfo {
+    pub(crate) rsdp_address: Option<usize>,
+    pub(crate) device_tree_address: Option<usize>,
+    pub(crate) secure_boot: Option<bool>,
+    pub(crate) tsc_frequency: Option<u64>,
+    pub(crate) bootloader_image: Option<MemoryRegion>,
+    /// The UTF-8 encoded firmware vendor string.
+    firmware_vendor: [u8; MAX_FIRMWARE_VENDOR_LEN],
+    firmware_vendor_len: usize,
+    pub(crate) firmware_revision: u32,
+}
+
+impl SystemInfo {
+    pub(crate) fn new(
+        system_table: &SystemTable<Boot>,
+        rsdp_address: Option<usize>,
+        device_tree_address: Option<usize>,
+        secure_boot: Option<bool>,
+        tsc_frequency: Option<u64>,
+        bootloader_image: Option<MemoryRegion>,
+    ) -> Self {
+        // The vendor string is UCS-2 encoded, so it's converted to UTF-8. It is
+        // truncated at a character boundary if it's too long.
+        let mut firmware_vendor = [0; MAX_FIRMWARE_VENDOR_LEN];
+        let mut firmware_vendor_len = 0;
+        for c16 in system_table.firmware_vendor().iter() {
+            let c = char::from(*c16);
+            if firmware_vendor_len + c.len_utf8() > firmware_vendor.len() {
+                break;
+            }
+            firmware_vendor_len += c
+                .encode_utf8(&mut firmware_vendor[firmware_vendor_len..])
+                .len();
+        }
+
+        Self {
+            rsdp_address,
+            device_tree_address,
+            secure_boot,
+            tsc_frequency,
+            bootloader_image,
+            firmware_vendor,
+            firmware_vendor_len,
+            firmware_revision: system_table.firmware_revision(),
+        }
+    }
+
+    pub(crate) fn firmware_vendor(&self) -> &str {
+        str::from_utf8(&self.firmware_vendor[..self.firmware_vendor_len])
+            .expect("firmware vendor is not valid UTF-8")
+    }
+}
 
 impl RuntimeContext {
     pub(crate) fn create_boot_info(
-        mut self,
+        &mut self,
         frame_buffer: Option<FrameBuffer>,
-        rsdp_address: Option<usize>,
+        system_info: &SystemInfo,
         modules: &'static [Module],
         elf_sections: &'static [ElfSection],
     ) -> &'static BootInformation {
@@ -35,15 +98,141 @@ impl RuntimeContext {
             .extend(elf_sections_layout)
             .expect("failed to extend boot info layout with elf sections");
 
-        let boot_info_address = self.page_allocator.get_free_address(combined.size());
+        let firmware_vendor = system_info.firmware_vendor().as_bytes();
+        let firmware_vendor_layout = Layout::array::<u8>(firmware_vendor.len())
+            .expect("failed to create firmware vendor layout");
+        let (combined, firmware_vendor_offset) = combined
+            .extend(firmware_vendor_layout)
+            .expect("failed to extend boot info layout with firmware vendor");
 
+        let env = self.config.env;
+        let env_entries_layout = Layout::array::<BootConfigEntry>(env.len())
+            .expect("failed to create boot config layout");
+        let (combined, env_entries_offset) = combined
+            .extend(env_entries_layout)
+            .expect("failed to extend boot info layout with boot config");
+        let env_bytes_len = env.iter().map(|(key, value)| key.len() + value.len()).sum();
+        let env_bytes_layout = Layout::array::<u8>(env_bytes_len)
+            .expect("failed to create boot config strings layout");
+        let (combined, env_bytes_offset) = combined
+            .extend(env_bytes_layout)
+            .expect("failed to extend boot info layout with boot config strings");
+
+        // If the boot info is protected, it's surrounded by unmapped guard pages.
+        let protect = self.config.protect_boot_info;
+        let guard_size = if protect { PAGE_SIZE } else { 0 };
+
+        let boot_info_address = match self.config.boot_info_vaddr {
+            Some(address) => {
+                let address = VirtualAddress::new(address).unwrap_or_else(|| {
+                    panic!("boot info virtual address {address:#x} is non-canonical")
+                });
+                assert!(
+                    address.value() % PAGE_SIZE == 0,
+                    "boot info virtual address {address:#x} isn't page aligned"
+                );
+                assert!(
+                    address.value() >= guard_size,
+                    "boot info virtual address {address:#x} leaves no room for a guard page"
+                );
+                address
+            }
+            None => {
+                self.page_allocator
+                    .get_free_address(combined.size() + 2 * guard_size)
+                    + guard_size
+            }
+        };
+
+        let boot_info_end = boot_info_address
+            .checked_add(combined.size() - 1)
+            .unwrap_or_else(|| {
+                panic!("boot info at {boot_info_address:#x} extends past the address space")
+            });
         let pages = PageRange::new(
             Page::containing_address(boot_info_address),
-            Page::containing_address(boot_info_address + combined.size() - 1),
+            Page::containing_address(boot_info_end),
         );
 
+        let guard_pages = protect.then(|| {
+            (
+                Page::containing_address(boot_info_address - PAGE_SIZE),
+                Page::containing_address(boot_info_end) + 1,
+            )
+        });
+
         let mut bootloader_page_tables = Mapper::current(&mut self.frame_allocator);
         let flags = PteFlags::new().present(true).writable(true);
+        // The bootloader writes the boot info through its own mapping, so the kernel's
+        // mapping can be read-only from the start.
+        let kernel_flags = if protect {
+            PteFlags::new().present(true)
+        } else {
+            flags
+        };
+
+        // A dynamically chosen address is guaranteed to be free.
+        if self.config.boot_info_vaddr.is_some() {
+            if let Some((before, after)) = guard_pages {
+                for page in [before, after] {
+                    assert!(
+                        self.mapper.translate(page).is_none(),
+                        "boot info guard {page:?} collides with an existing kernel mapping"
+                    );
+                }
+            }
+            for page in pages.clone() {
+                assert!(
+                    self.mapper.translate(page).is_none(),
+                    "boot info {page:?} collides with an existing kernel mapping"
+                );
+                assert!(
+                    bootloader_page_tables.translate(page).is_none(),
+                    "boot info {page:?} collides with an existing bootloader mapping"
+                );
+            }
+        }
+
+        // The sub-slices are constructed from the offsets, so make sure they don't
+        // extend past the mapped pages.
+        let mapped_size = pages.size_in_bytes();
+        for (name, offset, layout) in [
+            ("boot info", 0, boot_info_layout),
+            (
+                "memory regions",
+                memory_regions_offset,
+                memory_regions_layout,
+            ),
+            ("modules", modules_offset, modules_layout),
+            ("elf sections", elf_sections_offset, elf_sections_layout),
+            (
+                "firmware vendor",
+                firmware_vendor_offset,
+                firmware_vendor_layout,
+            ),
+            ("boot config", env_entries_offset, env_entries_layout),
+            ("boot config strings", env_bytes_offset, env_bytes_layout),
+        ] {
+            assert!(
+                offset + layout.size() <= mapped_size,
+                "boot info {name} at {offset:#x}..{:#x} extends past the mapped {mapped_size:#x} \
+                 bytes",
+                offset + layout.size()
+            );
+        }
+
+        self.mappings
+            .record("boot info", pages.clone(), Target::Any, kernel_flags);
+        if let Some((before, after)) = guard_pages {
+            for page in [before, after] {
+                self.mappings.record(
+                    "boot info guard",
+                    PageRange::new(page, page),
+                    Target::Unmapped,
+                    kernel_flags,
+                );
+            }
+        }
 
         for page in pages {
             let frame = self
@@ -51,13 +240,16 @@ impl RuntimeContext {
                 .allocate_frame()
                 .expect("failed to allocate boot info frame");
             self.mapper
-                .map(page, frame, flags, &mut self.frame_allocator);
+                .map(page, frame, kernel_flags, &mut self.frame_allocator);
             bootloader_page_tables.map(page, frame, flags, &mut self.frame_allocator);
         }
 
         let memory_map_regions_address = boot_info_address + memory_regions_offset;
         let modules_address = boot_info_address + modules_offset;
         let elf_sections_address = boot_info_address + elf_sections_offset;
+        let firmware_vendor_address = boot_info_address + firmware_vendor_offset;
+        let env_entries_address = boot_info_address + env_entries_offset;
+        let env_bytes_address = boot_info_address + env_bytes_offset;
 
         let uninit_boot_info: &'static mut MaybeUninit<BootInformation> =
             // SAFETY: We allocated it.
@@ -77,22 +269,68 @@ impl RuntimeContext {
             slice::from_raw_parts_mut(elf_sections_address.value() as *mut _, elf_sections.len())
         };
 
+        // SAFETY: We allocated it.
+        let mut uninit_firmware_vendor: &'static mut [MaybeUninit<u8>] = unsafe {
+            slice::from_raw_parts_mut(
+                firmware_vendor_address.value() as *mut _,
+                firmware_vendor.len(),
+            )
+        };
+
+        // SAFETY: We allocated it.
+        let uninit_env_entries: &'static mut [MaybeUninit<BootConfigEntry>] =
+            unsafe { slice::from_raw_parts_mut(env_entries_address.value() as *mut _, env.len()) };
+        // SAFETY: We allocated it.
+        let mut uninit_env_bytes: &'static mut [MaybeUninit<u8>] = unsafe {
+            slice::from_raw_parts_mut(env_bytes_address.value() as *mut _, env_bytes_len)
+        };
+
         let memory_regions = self
             .frame_allocator
             .construct_memory_map(uninit_memory_regions)
             .into();
         let modules = MaybeUninit::write_slice(uninit_modules, modules).into();
         let elf_sections = MaybeUninit::write_slice(uninit_elf_sections, elf_sections).into();
+        let firmware_vendor = copy_str(&mut uninit_firmware_vendor, system_info.firmware_vendor());
+        for ((key, value), entry) in env.iter().zip(uninit_env_entries.iter_mut()) {
+            entry.write(BootConfigEntry {
+                key: copy_str(&mut uninit_env_bytes, key),
+                value: copy_str(&mut uninit_env_bytes, value),
+            });
+        }
+        // SAFETY: We initialised the entries.
+        let config = unsafe { MaybeUninit::slice_assume_init_mut(uninit_env_entries) }.into();
 
-        uninit_boot_info.write({
+        let boot_info = uninit_boot_info.write({
             BootInformation {
+                magic: BOOT_INFORMATION_MAGIC,
+                // The checksum is computed once all other fields are populated.
+                checksum: 0,
                 size: combined.size(),
                 frame_buffer,
-                rsdp_address,
+                rsdp_address: system_info.rsdp_address,
+                device_tree_address: system_info.device_tree_address,
+                page_table_address: self.mapper.frame().start_address().value(),
+                secure_boot: system_info.secure_boot,
+                tsc_frequency: system_info.tsc_frequency,
+                bootloader_image: system_info.bootloader_image,
+                kernel_identity_map: self.kernel_identity_map,
+                firmware_vendor,
+                firmware_revision: system_info.firmware_revision,
+                config,
                 memory_regions,
                 modules,
                 elf_sections,
             }
-        })
+        });
+        boot_info.checksum = boot_info.compute_checksum();
+        boot_info
     }
 }
+
+/// Copies `s` into the start of `buffer`, advancing `buffer` past the copy.
+fn copy_str(buffer: &mut &'static mut [MaybeUninit<u8>], s: &str) -> &'static str {
+    let (copy, rest) = mem::take(buffer).split_at_mut(s.len());
+    *buffer = rest;
+    str::from_utf8(MaybeUninit::write_slice(copy, s.as_bytes())).expect("string is not valid UTF-8")
+}
@@ -0,0 +1,82 @@
+//! Loading a kernel that a prior boot stage placed in memory.
+//!
+//! The prior stage sets the `UefiBootloaderKernel` variable to the kernel's
+//! physical address followed by its length, both as little-endian `u64`s.
+
+use crate::{firmware, memory::PAGE_SIZE, BootContext};
+use log::info;
+use uefi::{
+    cstr16, guid,
+    table::{boot::MemoryType, runtime::VariableVendor},
+};
+
+/// The vendor of the variable holding the kernel's location.
+const VENDOR: VariableVendor = VariableVendor(guid!("5c6b1a3e-8f2d-4e07-9b3a-2d6e4f1c7a90"));
+
+/// Memory types that a prior stage may have allocated the kernel in.
+///
+/// Conventional memory isn't accepted as the bootloader's own allocations
+/// could overwrite the kernel before it's parsed.
+const ALLOCATED_TYPES: [MemoryType; 3] = [
+    MemoryType::LOADER_CODE,
+    MemoryType::LOADER_DATA,
+    MemoryType::BOOT_SERVICES_DATA,
+];
+
+impl BootContext {
+    /// Returns the kernel placed in memory by a prior boot stage.
+    pub(crate) fn in_memory_kernel(&self) -> &'static [u8] {
+        let mut buffer = [0; 16];
+        let (value, _) = self
+            .system_table
+            .runtime_services()
+            .get_variable(cstr16!("UefiBootloaderKernel"), &VENDOR, &mut buffer)
+            .expect("failed to read in-memory kernel variable");
+        assert_eq!(value.len(), 16, "invalid in-memory kernel variable");
+
+        let mut start = [0; 8];
+        let mut len = [0; 8];
+        start.copy_from_slice(&buffer[..8]);
+        len.copy_from_slice(&buffer[8..]);
+        let start = u64::from_le_bytes(start);
+        let len = u64::from_le_bytes(len);
+        let end = start
+            .checked_add(len)
+            .expect("in-memory kernel overflows address space");
+        assert!(len != 0, "in-memory kernel is empty");
+        assert!(
+            self.is_allocated_memory(start, end),
+            "in-memory kernel at {start:#x}..{end:#x} isn't in allocated memory"
+        );
+        info!("using in-memory kernel at {start:#x}..{end:#x}");
+
+        // SAFETY: The region was checked to be allocated memory, and the prior stage
+        // doesn't use it once it has passed control to the bootloader.
+        unsafe { core::slice::from_raw_parts(start as *const u8, len as usize) }
+    }
+
+    /// Returns whether `start..end` is covered by allocated memory.
+    fn is_allocated_memory(&self, start: u64, end: u64) -> bool {
+        let size = firmware::memory_map_size(&self.system_table);
+        let buffer = self.allocate_byte_slice(size, MemoryType::LOADER_DATA);
+        let memory_map = firmware::memory_map(&self.system_table, buffer);
+
+        // The region may span adjacent descriptors, so the covered prefix is extended
+        // until it reaches the end of the region.
+        let mut covered = start;
+        while covered < end {
+            let next = memory_map.clone().find_map(|descriptor| {
+                let descriptor_end =
+                    descriptor.phys_start + descriptor.page_count * PAGE_SIZE as u64;
+                (ALLOCATED_TYPES.contains(&descriptor.ty)
+                    && (descriptor.phys_start..descriptor_end).contains(&covered))
+                .then_some(descriptor_end)
+            });
+            match next {
+                Some(next) => covered = next,
+                None => return false,
+            }
+        }
+        true
+    }
+}
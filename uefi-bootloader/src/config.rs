@@ -0,0 +1,718 @@
+use crate::{memory::PAGE_SIZE, BootContext};
+use log::{error, warn};
+use uefi::{
+    prelude::cstr16,
+    proto::media::file::{File, FileAttribute, FileMode, RegularFile},
+    table::boot::MemoryType,
+    CStr16,
+};
+
+const CONFIG_NAME: &CStr16 = cstr16!("boot.cfg");
+
+/// The keys that choose where the kernel is loaded from, which have no effect
+/// when the kernel is embedded.
+const KERNEL_SOURCE_KEYS: [&str; 4] = [
+    "kernel",
+    "kernel_in_memory",
+    "kernel_manifest",
+    "ext2_kernel",
+];
+
+/// The bootloader configuration.
+///
+/// The configuration is read from `boot.cfg` in the root of the boot volume.
+/// Each line of the file has the form `key = value`. Empty lines and lines
+/// starting with `#` are ignored. If the file doesn't exist, the default
+/// configuration is used.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Config {
+    /// The path of the kernel on the boot volume.
+    pub(crate) kernel: &'static str,
+    /// The directory containing the modules.
+    pub(crate) modules_dir: &'static str,
+    /// Whether modules in subdirectories of the modules directory are loaded.
+    pub(crate) modules_recursive: bool,
+    /// Whether modules that fail to load are skipped rather than aborting the
+    /// boot.
+    ///
+    /// Skipped modules are logged and aren't passed to the kernel. This only
+    /// applies to modules on the boot volume, as failing to read an ext2
+    /// partition always aborts the boot.
+    pub(crate) modules_optional: bool,
+    /// The alignment of modules' physical start addresses, set using
+    /// `module_align.<name> = <alignment>`.
+    pub(crate) module_aligns: ModuleAligns,
+    /// The size of the kernel stack in bytes.
+    ///
+    /// This is overridden by the kernel's stack note, if present.
+    pub(crate) stack_size: usize,
+    /// The alignment of the top of the kernel stack in bytes.
+    ///
+    /// This is overridden by the kernel's stack note, if present.
+    pub(crate) stack_align: usize,
+    /// The number of unmapped guard pages below the kernel stack.
+    ///
+    /// A single guard page can be skipped by a function with a large stack
+    /// frame, so kernels that use large stack frames should increase this.
+    pub(crate) stack_guard_pages: usize,
+    /// Whether log records are appended to `boot.log` in the root of the boot
+    /// volume.
+    ///
+    /// Only records logged before exiting boot services are written.
+    pub(crate) boot_log: bool,
+    /// Whether only errors are logged.
+    ///
+    /// This suppresses progress messages, including the boot information and
+    /// memory map dump, and the screen is only cleared if an error is logged.
+    /// Records logged while the configuration is being loaded aren't
+    /// affected. The framebuffer is still passed to the kernel.
+    pub(crate) quiet: bool,
+    /// Whether the framebuffer is mapped into the kernel's address space.
+    ///
+    /// Kernels that map the framebuffer themselves (e.g. with a specific cache
+    /// policy) can disable this, in which case only the physical address is
+    /// reported.
+    pub(crate) map_frame_buffer: bool,
+    /// The graphics mode to use.
+    pub(crate) resolution: Resolution,
+    /// Whether a test pattern is briefly drawn to the framebuffer before it's
+    /// used for logging.
+    ///
+    /// This helps diagnose an incorrect framebuffer stride or pixel format.
+    pub(crate) test_pattern: bool,
+    /// Whether the framebuffer is cleared before logging to it.
+    ///
+    /// This can be disabled to keep a firmware splash visible, in which case
+    /// log records are written over it.
+    pub(crate) clear_screen: bool,
+    /// The virtual address of the boot information.
+    ///
+    /// The address must be page aligned and mustn't overlap the kernel or any
+    /// other mapping. If unset, a free address is chosen.
+    pub(crate) boot_info_vaddr: Option<usize>,
+    /// Whether the boot information is mapped read-only in the kernel's
+    /// address space and surrounded by unmapped guard pages.
+    ///
+    /// This catches kernel bugs that write to or past the boot information.
+    pub(crate) protect_boot_info: bool,
+    /// The format of the information passed to the kernel.
+    pub(crate) handoff: Handoff,
+    /// How kernel segments in the lower half of the address space are
+    /// handled.
+    pub(crate) higher_half: HigherHalf,
+    /// Whether the kernel was placed in memory by a prior boot stage.
+    ///
+    /// If set, the kernel's physical address and length are read from the
+    /// `UefiBootloaderKernel` variable rather than loading it from a file.
+    pub(crate) kernel_in_memory: bool,
+    /// Whether the kernel's first executable segment is also identity-mapped
+    /// at its physical address.
+    pub(crate) identity_map_kernel: bool,
+    /// Whether frames allocated after exiting boot services are taken from
+    /// high or low physical memory.
+    ///
+    /// This affects the page tables, stack, and boot information. The kernel's
+    /// segments and modules are allocated by the firmware, which decides
+    /// their placement.
+    ///
+    /// [`TopDown`](AllocPolicy::TopDown) can't be used with the
+    /// [`Multiboot2Info`](Handoff::Multiboot2Info) handoff, whose information
+    /// structure must be below 4 GiB.
+    pub(crate) alloc_policy: AllocPolicy,
+    /// The path of a manifest describing a kernel made of raw binaries.
+    ///
+    /// If unset, the kernel is loaded from an ELF file.
+    pub(crate) kernel_manifest: Option<&'static str>,
+    /// The kernel command line.
+    ///
+    /// This is only passed to the kernel when using the
+    /// [`Multiboot2Info`](Handoff::Multiboot2Info) handoff.
+    pub(crate) cmdline: &'static str,
+    /// Whether to check the kernel's page table and halt rather than jumping
+    /// to the kernel.
+    ///
+    /// The result of the check is logged.
+    pub(crate) selftest: bool,
+    /// Key/value pairs passed to the kernel, set using `env.<key> = <value>`.
+    pub(crate) env: Env,
+    /// Whether frames allocated after exiting boot services (e.g. for the
+    /// kernel stack and boot information) are zeroed before being used.
+    ///
+    /// This prevents stale data from leaking into the kernel, at the cost of
+    /// writing every allocated frame.
+    pub(crate) zero_frames: bool,
+    /// The physical address above which usable memory is ignored.
+    ///
+    /// This is useful for testing how the kernel behaves with little memory.
+    /// The bootloader only allocates memory below the limit, and usable
+    /// memory above it is reported as bootloader memory.
+    pub(crate) max_usable_memory: Option<usize>,
+    /// The index of the disk containing the ext2 partition, counting only
+    /// whole disks with media present.
+    pub(crate) ext2_disk: usize,
+    /// The byte offset of the ext2 partition from the start of its disk.
+    pub(crate) ext2_offset: Option<usize>,
+    /// The path of the kernel on the ext2 partition.
+    ///
+    /// If unset, the kernel is loaded from the boot volume.
+    pub(crate) ext2_kernel: Option<&'static str>,
+    /// The path of the modules directory on the ext2 partition.
+    ///
+    /// If unset, the modules are loaded from the boot volume.
+    pub(crate) ext2_modules_dir: Option<&'static str>,
+}
+
+/// The maximum number of modules that can have a custom alignment.
+const MAX_MODULE_ALIGNS: usize = 16;
+
+/// The alignments of modules' physical start addresses.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ModuleAligns {
+    entries: [(&'static str, usize); MAX_MODULE_ALIGNS],
+    len: usize,
+}
+
+impl ModuleAligns {
+    const fn new() -> Self {
+        Self {
+            entries: [("", 0); MAX_MODULE_ALIGNS],
+            len: 0,
+        }
+    }
+
+    /// Returns the alignment of the module with the given name.
+    ///
+    /// Modules are page aligned by default.
+    pub(crate) fn get(&self, name: &str) -> usize {
+        self.entries[..self.len]
+            .iter()
+            .rev()
+            .find(|(module, _)| *module == name)
+            .map_or(PAGE_SIZE, |(_, align)| *align)
+    }
+
+    fn insert(&mut self, name: &'static str, align: usize) -> bool {
+        if self.len == MAX_MODULE_ALIGNS {
+            return false;
+        }
+        self.entries[self.len] = (name, align.max(PAGE_SIZE));
+        self.len += 1;
+        true
+    }
+}
+
+/// The maximum number of key/value pairs that can be passed to the kernel.
+const MAX_ENV_ENTRIES: usize = 32;
+
+/// Key/value pairs passed to the kernel.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Env {
+    entries: [(&'static str, &'static str); MAX_ENV_ENTRIES],
+    len: usize,
+}
+
+impl Env {
+    const fn new() -> Self {
+        Self {
+            entries: [("", ""); MAX_ENV_ENTRIES],
+            len: 0,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &(&'static str, &'static str)> {
+        self.entries[..self.len].iter()
+    }
+
+    /// Sets the value of the key, overwriting any previous value.
+    fn insert(&mut self, key: &'static str, value: &'static str) -> bool {
+        if let Some(entry) = self.entries[..self.len]
+            .iter_mut()
+            .find(|(existing, _)| *existing == key)
+        {
+            entry.1 = value;
+            return true;
+        }
+
+        if self.len == MAX_ENV_ENTRIES {
+            return false;
+        }
+        self.entries[self.len] = (key, value);
+        self.len += 1;
+        true
+    }
+}
+
+/// The graphics mode to use.
+///
+/// If setting a mode fails, progressively smaller modes are tried before
+/// falling back to the firmware's current mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Resolution {
+    /// The firmware's current mode.
+    Current,
+    /// The mode with the largest resolution.
+    Max,
+    /// The mode with the largest resolution no larger than the given width
+    /// and height.
+    Preferred(usize, usize),
+}
+
+/// The format of the information passed to the kernel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Handoff {
+    /// A [`BootInformation`](uefi_bootloader_api::BootInformation) is passed
+    /// to the kernel.
+    Native,
+    /// A Multiboot2 information structure is passed to a 64-bit kernel.
+    ///
+    /// Only the information structure follows the Multiboot2 specification.
+    /// The kernel is entered at its ELF entry point in long mode with paging
+    /// enabled and boot services exited, the same as the native handoff, with
+    /// the magic value in `eax` and `rdi` and the structure's address in `ebx`
+    /// and `rsi`. The kernel's Multiboot2 header isn't read, so kernels that
+    /// rely on the specification's i386 or EFI amd64 machine state aren't
+    /// supported.
+    ///
+    /// The information structure and modules are placed below 4 GiB, as the
+    /// structure only holds 32-bit addresses.
+    Multiboot2Info,
+}
+
+/// Where frames allocated by the bootloader are placed in physical memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum AllocPolicy {
+    /// Frames are allocated from the lowest usable memory, packing
+    /// allocations together.
+    BottomUp,
+    /// Frames are allocated from the highest usable memory, keeping low memory
+    /// free for DMA buffers and AP trampolines.
+    TopDown,
+}
+
+/// How kernel segments in the lower half of the address space are handled.
+///
+/// Higher-half kernels leave the lower half for userspace, so a segment in the
+/// lower half is almost always a linker script mistake.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum HigherHalf {
+    /// The kernel can be linked anywhere.
+    Off,
+    /// A warning is logged for each segment in the lower half.
+    Warn,
+    /// The bootloader refuses to boot a kernel with a segment in the lower
+    /// half.
+    Require,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            kernel: "kernel.elf",
+            modules_dir: "modules",
+            modules_recursive: false,
+            modules_optional: false,
+            module_aligns: ModuleAligns::new(),
+            stack_size: 18 * PAGE_SIZE,
+            stack_align: PAGE_SIZE,
+            stack_guard_pages: 1,
+            boot_log: false,
+            quiet: false,
+            map_frame_buffer: true,
+            resolution: Resolution::Current,
+            test_pattern: false,
+            clear_screen: true,
+            boot_info_vaddr: None,
+            protect_boot_info: false,
+            handoff: Handoff::Native,
+            higher_half: HigherHalf::Off,
+            kernel_in_memory: false,
+            identity_map_kernel: false,
+            alloc_policy: AllocPolicy::BottomUp,
+            kernel_manifest: None,
+            cmdline: "",
+            selftest: false,
+            env: Env::new(),
+            zero_frames: false,
+            max_usable_memory: None,
+            ext2_disk: 0,
+            ext2_offset: None,
+            ext2_kernel: None,
+            ext2_modules_dir: None,
+        }
+    }
+}
+
+impl Config {
+    /// Parses the configuration, halting if any line is invalid.
+    ///
+    /// Every line is checked before halting so that all mistakes are reported
+    /// at once. Unknown keys only produce a warning so that configurations
+    /// written for newer versions of the bootloader still work.
+    fn parse(source: &'static str) -> Self {
+        let mut config = Self::default();
+        let mut errors = 0;
+
+        for (i, line) in source.lines().enumerate() {
+            let line_number = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => {
+                    error!("malformed line {line_number} in boot.cfg: {line}");
+                    errors += 1;
+                    continue;
+                }
+            };
+
+            if cfg!(feature = "embedded-kernel") && KERNEL_SOURCE_KEYS.contains(&key) {
+                warn!("ignoring {key} on line {line_number} in boot.cfg as the kernel is embedded");
+            }
+
+            match key {
+                "kernel" => config.kernel = value,
+                "modules_dir" => config.modules_dir = value,
+                "modules_recursive" => set(
+                    &mut config.modules_recursive,
+                    parse_bool(value),
+                    key,
+                    line_number,
+                    &mut errors,
+                ),
+                "modules_optional" => set(
+                    &mut config.modules_optional,
+                    parse_bool(value),
+                    key,
+                    line_number,
+                    &mut errors,
+                ),
+                "stack_size" => set(
+                    &mut config.stack_size,
+                    parse_usize(value).filter(|size| is_valid_stack_size(*size)),
+                    key,
+                    line_number,
+                    &mut errors,
+                ),
+                "stack_align" => set(
+                    &mut config.stack_align,
+                    parse_usize(value).filter(|align| align.is_power_of_two()),
+                    key,
+                    line_number,
+                    &mut errors,
+                ),
+                "stack_guard_pages" => set(
+                    &mut config.stack_guard_pages,
+                    parse_usize(value),
+                    key,
+                    line_number,
+                    &mut errors,
+                ),
+                "boot_log" => set(
+                    &mut config.boot_log,
+                    parse_bool(value),
+                    key,
+                    line_number,
+                    &mut errors,
+                ),
+                "quiet" => set(
+                    &mut config.quiet,
+                    parse_bool(value),
+                    key,
+                    line_number,
+                    &mut errors,
+                ),
+                "map_frame_buffer" => set(
+                    &mut config.map_frame_buffer,
+                    parse_bool(value),
+                    key,
+                    line_number,
+                    &mut errors,
+                ),
+                "resolution" => set(
+                    &mut config.resolution,
+                    parse_resolution(value),
+                    key,
+                    line_number,
+                    &mut errors,
+                ),
+                "test_pattern" => set(
+                    &mut config.test_pattern,
+                    parse_bool(value),
+                    key,
+                    line_number,
+                    &mut errors,
+                ),
+                "clear_screen" => set(
+                    &mut config.clear_screen,
+                    parse_bool(value),
+                    key,
+                    line_number,
+                    &mut errors,
+                ),
+                "boot_info_vaddr" => set(
+                    &mut config.boot_info_vaddr,
+                    parse_usize(value).map(Some),
+                    key,
+                    line_number,
+                    &mut errors,
+                ),
+                "protect_boot_info" => set(
+                    &mut config.protect_boot_info,
+                    parse_bool(value),
+                    key,
+                    line_number,
+                    &mut errors,
+                ),
+                "handoff" => set(
+                    &mut config.handoff,
+                    parse_handoff(value),
+                    key,
+                    line_number,
+                    &mut errors,
+                ),
+                "higher_half" => set(
+                    &mut config.higher_half,
+                    parse_higher_half(value),
+                    key,
+                    line_number,
+                    &mut errors,
+                ),
+                "kernel_in_memory" => set(
+                    &mut config.kernel_in_memory,
+                    parse_bool(value),
+                    key,
+                    line_number,
+                    &mut errors,
+                ),
+                "identity_map_kernel" => set(
+                    &mut config.identity_map_kernel,
+                    parse_bool(value),
+                    key,
+                    line_number,
+                    &mut errors,
+                ),
+                "alloc_policy" => set(
+                    &mut config.alloc_policy,
+                    parse_alloc_policy(value),
+                    key,
+                    line_number,
+                    &mut errors,
+                ),
+                "kernel_manifest" => config.kernel_manifest = Some(value),
+                "cmdline" => config.cmdline = value,
+                "selftest" => set(
+                    &mut config.selftest,
+                    parse_bool(value),
+                    key,
+                    line_number,
+                    &mut errors,
+                ),
+                "zero_frames" => set(
+                    &mut config.zero_frames,
+                    parse_bool(value),
+                    key,
+                    line_number,
+                    &mut errors,
+                ),
+                "max_usable_memory" => set(
+                    &mut config.max_usable_memory,
+                    parse_usize(value).map(Some),
+                    key,
+                    line_number,
+                    &mut errors,
+                ),
+                "ext2_disk" => set(
+                    &mut config.ext2_disk,
+                    parse_usize(value),
+                    key,
+                    line_number,
+                    &mut errors,
+                ),
+                "ext2_offset" => set(
+                    &mut config.ext2_offset,
+                    parse_usize(value).map(Some),
+                    key,
+                    line_number,
+                    &mut errors,
+                ),
+                "ext2_kernel" => config.ext2_kernel = Some(value),
+                "ext2_modules_dir" => config.ext2_modules_dir = Some(value),
+                _ => {
+                    if let Some(name) = key.strip_prefix("env.") {
+                        config.set_env(name, value, key, line_number, &mut errors);
+                    } else if let Some(name) = key.strip_prefix("module_align.") {
+                        config.set_module_align(name, value, key, line_number, &mut errors);
+                    } else {
+                        warn!("ignoring unknown key on line {line_number} in boot.cfg: {key}");
+                    }
+                }
+            }
+        }
+
+        // The multiboot2 information must be below 4 GiB, which top-down allocation
+        // doesn't guarantee.
+        if config.handoff == Handoff::Multiboot2Info && config.alloc_policy == AllocPolicy::TopDown
+        {
+            error!(
+                "alloc_policy = top_down can't be used with handoff = multiboot2_info in boot.cfg"
+            );
+            errors += 1;
+        }
+
+        assert!(errors == 0, "boot.cfg has {errors} invalid lines");
+        config
+    }
+
+    fn set_env(
+        &mut self,
+        name: &'static str,
+        value: &'static str,
+        key: &str,
+        line_number: usize,
+        errors: &mut usize,
+    ) {
+        if name.is_empty() {
+            error!("empty key on line {line_number} in boot.cfg");
+            *errors += 1;
+        } else if !self.env.insert(name, value) {
+            error!("too many env entries for {key} on line {line_number} in boot.cfg");
+            *errors += 1;
+        }
+    }
+
+    fn set_module_align(
+        &mut self,
+        name: &'static str,
+        value: &str,
+        key: &str,
+        line_number: usize,
+        errors: &mut usize,
+    ) {
+        match parse_usize(value).filter(|align| align.is_power_of_two()) {
+            Some(align) => {
+                if !self.module_aligns.insert(name, align) {
+                    error!(
+                        "too many module alignments for {key} on line {line_number} in boot.cfg"
+                    );
+                    *errors += 1;
+                }
+            }
+            None => {
+                error!("invalid value for {key} on line {line_number} in boot.cfg: {value}");
+                *errors += 1;
+            }
+        }
+    }
+}
+
+fn set<T>(field: &mut T, value: Option<T>, key: &str, line_number: usize, errors: &mut usize) {
+    match value {
+        Some(value) => *field = value,
+        None => {
+            error!("invalid value for {key} on line {line_number} in boot.cfg");
+            *errors += 1;
+        }
+    }
+}
+
+/// The maximum size of the kernel stack in bytes.
+const MAX_STACK_SIZE: usize = 64 * 1024 * 1024;
+
+pub(crate) fn is_valid_stack_size(size: usize) -> bool {
+    size > 0 && size <= MAX_STACK_SIZE
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parses `current`, `max`, or a resolution of the form `<width>x<height>`.
+fn parse_resolution(value: &str) -> Option<Resolution> {
+    match value {
+        "current" => Some(Resolution::Current),
+        "max" => Some(Resolution::Max),
+        _ => {
+            let (width, height) = value.split_once('x')?;
+            Some(Resolution::Preferred(
+                width.parse().ok()?,
+                height.parse().ok()?,
+            ))
+        }
+    }
+}
+
+fn parse_handoff(value: &str) -> Option<Handoff> {
+    match value {
+        "native" => Some(Handoff::Native),
+        "multiboot2_info" => Some(Handoff::Multiboot2Info),
+        _ => None,
+    }
+}
+
+fn parse_alloc_policy(value: &str) -> Option<AllocPolicy> {
+    match value {
+        "bottom_up" => Some(AllocPolicy::BottomUp),
+        "top_down" => Some(AllocPolicy::TopDown),
+        _ => None,
+    }
+}
+
+fn parse_higher_half(value: &str) -> Option<HigherHalf> {
+    match value {
+        "off" => Some(HigherHalf::Off),
+        "warn" => Some(HigherHalf::Warn),
+        "require" => Some(HigherHalf::Require),
+        _ => None,
+    }
+}
+
+/// Parses a decimal or `0x`-prefixed hexadecimal number.
+pub(crate) fn parse_usize(value: &str) -> Option<usize> {
+    match value.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+impl BootContext {
+    pub(crate) fn load_config(&self) -> Config {
+        let mut root = self
+            .open_file_system_root()
+            .expect("failed to open file system root");
+
+        let mut file = match root.open(CONFIG_NAME, FileMode::Read, FileAttribute::empty()) {
+            Ok(file) => file
+                .into_regular_file()
+                .expect("config file was closed or deleted"),
+            Err(_) => return Config::default(),
+        };
+
+        file.set_position(RegularFile::END_OF_FILE)
+            .expect("failed to set config file position to end of file");
+        let len = file
+            .get_position()
+            .expect("failed to get config file length") as usize;
+        if len == 0 {
+            return Config::default();
+        }
+        file.set_position(0)
+            .expect("failed to set config file position to start of file");
+
+        // The configuration borrows its strings from this slice.
+        let bytes = self.allocate_byte_slice(len, MemoryType::LOADER_DATA);
+        file.read(bytes).expect("failed to read config file");
+
+        Config::parse(core::str::from_utf8(bytes).expect("config file is not valid UTF-8"))
+    }
+}
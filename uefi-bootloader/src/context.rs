@@ -1,9 +1,12 @@
 use crate::{
+    config::Config,
+    firmware, logger,
     memory::{
-        Frame, FrameRange, LegacyFrameAllocator, Mapper, Page, PageAllocator, PageRange,
-        PhysicalAddress, PteFlags, UefiFrameAllocator, VirtualAddress, KERNEL_MEMORY,
+        allocate_type_below, Frame, FrameRange, LegacyFrameAllocator, Mapper, Page, PageAllocator,
+        PageRange, PhysicalAddress, PteFlags, UefiFrameAllocator, VirtualAddress, KERNEL_MEMORY,
     },
-    util::calculate_pages,
+    selftest::{Mappings, Target},
+    util::{calculate_pages, to_uefi_path, MAX_PATH_LEN},
 };
 use core::mem::MaybeUninit;
 use goblin::elf64::program_header::ProgramHeader;
@@ -11,7 +14,10 @@ use uefi::{
     proto::{
         device_path::DevicePath,
         loaded_image::LoadedImage,
-        media::{file::Directory, fs::SimpleFileSystem},
+        media::{
+            file::{Directory, File, FileAttribute, FileMode, RegularFile},
+            fs::SimpleFileSystem,
+        },
     },
     table::{
         boot::{AllocateType, MemoryType},
@@ -19,28 +25,48 @@ use uefi::{
     },
     Handle,
 };
+use uefi_bootloader_api::{KernelIdentityMap, MemoryRegion, MemoryRegionKind};
 
 /// Bootloader context before extiting boot services.
 pub(crate) struct BootContext {
     pub(crate) image_handle: Handle,
     pub(crate) system_table: SystemTable<Boot>,
+    pub(crate) config: Config,
     pub(crate) page_allocator: PageAllocator,
     pub(crate) mapper: Mapper,
+    pub(crate) mappings: Mappings,
+    pub(crate) kernel_identity_map: Option<KernelIdentityMap>,
 }
 
 impl BootContext {
     pub(crate) fn new(image_handle: Handle, system_table: SystemTable<Boot>) -> Self {
         let mut frame_allocator = UefiFrameAllocator {
             system_table: &system_table,
+            max_address: None,
         };
         let mapper = Mapper::new(&mut frame_allocator);
 
-        Self {
+        let mut context = Self {
             image_handle,
             system_table,
+            config: Config::default(),
             page_allocator: PageAllocator::new(),
             mapper,
+            mappings: Mappings::new(),
+            kernel_identity_map: None,
+        };
+        context.config = context.load_config();
+
+        // The page table was allocated before the configuration was loaded, so it's
+        // reallocated below the limit.
+        if let Some(max_address) = context.config.max_usable_memory {
+            context.mapper = Mapper::new(&mut UefiFrameAllocator {
+                system_table: &context.system_table,
+                max_address: Some(max_address),
+            });
         }
+
+        context
     }
 
     pub(crate) fn open_file_system_root(&self) -> Option<Directory> {
@@ -62,6 +88,44 @@ impl BootContext {
             .ok()
     }
 
+    /// Reads the file at `path` on the boot volume into newly allocated pages.
+    pub(crate) fn read_file(&self, path: &str, ty: MemoryType) -> Option<&'static mut [u8]> {
+        let mut path_buf = [0; MAX_PATH_LEN];
+        let path = to_uefi_path(path, &mut path_buf);
+
+        let mut file = self
+            .open_file_system_root()?
+            .open(path, FileMode::Read, FileAttribute::empty())
+            .ok()?
+            .into_regular_file()?;
+
+        file.set_position(RegularFile::END_OF_FILE).ok()?;
+        let len = file.get_position().ok()? as usize;
+        if len == 0 {
+            return Some(&mut []);
+        }
+        file.set_position(0).ok()?;
+
+        let bytes = self.allocate_byte_slice(len, ty);
+        file.read(bytes).ok()?;
+        Some(bytes)
+    }
+
+    /// Returns the physical memory occupied by the bootloader's image.
+    pub(crate) fn bootloader_image(&self) -> Option<MemoryRegion> {
+        let loaded_image = self
+            .system_table
+            .boot_services()
+            .open_protocol_exclusive::<LoadedImage>(self.image_handle)
+            .ok()?;
+        let (base, size) = loaded_image.info();
+        Some(MemoryRegion {
+            start: base as usize,
+            len: size as usize,
+            kind: MemoryRegionKind::BootloaderReclaimable,
+        })
+    }
+
     pub(crate) fn system_table(&self) -> &SystemTable<Boot> {
         &self.system_table
     }
@@ -92,7 +156,11 @@ impl BootContext {
         len: usize,
         memory_type: MemoryType,
     ) -> &'static mut [MaybeUninit<T>] {
-        self.allocate_slice_inner(len, AllocateType::AnyPages, memory_type)
+        self.allocate_slice_inner(
+            len,
+            allocate_type_below(self.config.max_usable_memory),
+            memory_type,
+        )
     }
 
     pub(crate) fn allocate_byte_slice(&self, len: usize, ty: MemoryType) -> &'static mut [u8] {
@@ -101,6 +169,31 @@ impl BootContext {
         unsafe { MaybeUninit::slice_assume_init_mut(slice) }
     }
 
+    /// Allocates a zeroed byte slice that ends below `max_address`, in
+    /// addition to the configured maximum usable memory.
+    pub(crate) fn allocate_byte_slice_below(
+        &self,
+        len: usize,
+        ty: MemoryType,
+        max_address: usize,
+    ) -> &'static mut [u8] {
+        let max_address = self
+            .config
+            .max_usable_memory
+            .map_or(max_address, |max_usable| max_usable.min(max_address));
+        let slice = self.allocate_slice_inner(len, allocate_type_below(Some(max_address)), ty);
+        // SAFETY: allocate_slice_inner zeroed the bytes so they are initialised.
+        unsafe { MaybeUninit::slice_assume_init_mut(slice) }
+    }
+
+    /// Allocates frames for the segment and maps them into the kernel's page
+    /// table, returning the segment's memory.
+    ///
+    /// The returned memory is accessed through UEFI's identity mapping, which
+    /// acts as a scratch mapping for copying the segment's contents. The
+    /// kernel's mapping is created with the segment's final permissions, so
+    /// read-only and executable segments are never mapped writable in the
+    /// kernel's page table.
     pub(crate) fn map_segment(&mut self, segment: &ProgramHeader) -> &'static mut [u8] {
         let in_page_offset = (segment.p_vaddr as usize) & 0xfff;
         let size_from_page_start = in_page_offset + segment.p_memsz as usize;
@@ -137,8 +230,7 @@ impl BootContext {
         let pages = PageRange::new(
             Page::containing_address(virtual_start),
             Page::containing_address(virtual_end_inclusive),
-        )
-        .into_iter();
+        );
         let frames = FrameRange::new(
             Frame::containing_address(physical_start),
             Frame::containing_address(physical_end_inclusive),
@@ -156,35 +248,111 @@ impl BootContext {
             flags = flags.writable(true);
         }
 
-        for (page, frame) in pages.zip(frames) {
+        self.mappings.record(
+            "kernel segment",
+            pages.clone(),
+            Target::Frames(*frames.start()),
+            flags,
+        );
+
+        for (page, frame) in pages.into_iter().zip(frames.clone()) {
             self.mapper.map(
                 page,
                 frame,
                 flags,
                 &mut UefiFrameAllocator {
                     system_table: &self.system_table,
+                    max_address: self.config.max_usable_memory,
                 },
             );
         }
 
+        let is_executable = segment.p_flags & 0x1 != 0;
+        if self.config.identity_map_kernel
+            && is_executable
+            && self.kernel_identity_map.is_none()
+            && physical_start.value() != virtual_start.value()
+        {
+            self.identity_map_segment(segment, physical_start, frames, flags);
+        }
+
         &mut slice[in_page_offset..]
     }
 
+    /// Additionally maps the segment at its physical address.
+    fn identity_map_segment(
+        &mut self,
+        segment: &ProgramHeader,
+        physical_start: PhysicalAddress,
+        frames: FrameRange,
+        flags: PteFlags,
+    ) {
+        // The identity-mapped pages mustn't be handed out for other mappings.
+        self.page_allocator.mark_segment_as_used(&ProgramHeader {
+            p_vaddr: physical_start.value() as u64,
+            p_memsz: segment.p_memsz,
+            ..ProgramHeader::default()
+        });
+
+        let page = |frame: &Frame| {
+            Page::containing_address(VirtualAddress::new_canonical(frame.start_address().value()))
+        };
+        self.mappings.record(
+            "kernel identity map",
+            PageRange::new(page(frames.start()), page(frames.end())),
+            Target::Frames(*frames.start()),
+            flags,
+        );
+
+        for frame in frames {
+            self.mapper.identity_map(
+                frame,
+                flags,
+                &mut UefiFrameAllocator {
+                    system_table: &self.system_table,
+                    max_address: self.config.max_usable_memory,
+                },
+            );
+        }
+
+        self.kernel_identity_map = Some(KernelIdentityMap {
+            physical: physical_start.value(),
+            virt: segment.p_vaddr as usize,
+            len: segment.p_memsz as usize,
+        });
+    }
+
     pub(crate) fn exit_boot_services(self) -> RuntimeContext {
-        let (_, memory_map) = self.system_table.exit_boot_services();
+        // Files can't be written to after exiting boot services.
+        if let Some(logger) = logger::LOGGER.get() {
+            logger.close_file();
+        }
+
+        let memory_map = firmware::exit_boot_services(self.system_table);
         RuntimeContext {
+            config: self.config,
             page_allocator: self.page_allocator,
-            frame_allocator: LegacyFrameAllocator::new(memory_map),
+            frame_allocator: LegacyFrameAllocator::new(
+                memory_map,
+                self.config.zero_frames,
+                self.config.max_usable_memory,
+                self.config.alloc_policy,
+            ),
             mapper: self.mapper,
+            mappings: self.mappings,
+            kernel_identity_map: self.kernel_identity_map,
         }
     }
 }
 
 /// Bootloader context after extiting boot services.
 pub(crate) struct RuntimeContext {
+    pub(crate) config: Config,
     pub(crate) page_allocator: PageAllocator,
     pub(crate) frame_allocator: LegacyFrameAllocator,
     pub(crate) mapper: Mapper,
+    pub(crate) mappings: Mappings,
+    pub(crate) kernel_identity_map: Option<KernelIdentityMap>,
 }
 
 impl RuntimeContext {
@@ -0,0 +1,568 @@
+//! A minimal read-only ext2/ext4 driver.
+//!
+//! The driver supports reading regular files and directories using either
+//! block maps or extent trees. It doesn't support inline data, encryption,
+//! compression, or meta block groups.
+
+use crate::{firmware, BootContext};
+use core::ops::ControlFlow;
+use log::warn;
+use uefi::{
+    proto::media::{block::BlockIO, disk::DiskIo},
+    table::boot::{MemoryType, ScopedProtocol},
+};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const SUPERBLOCK_MAGIC: u16 = 0xef53;
+/// The block size is `1024 << log_block_size`, which is at most 64 KiB.
+const MAX_LOG_BLOCK_SIZE: u32 = 6;
+
+const ROOT_INODE: u32 = 2;
+
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_RECOVER: u32 = 0x4;
+const INCOMPAT_EXTENTS: u32 = 0x40;
+const INCOMPAT_64BIT: u32 = 0x80;
+const INCOMPAT_FLEX_BG: u32 = 0x200;
+const SUPPORTED_INCOMPAT: u32 =
+    INCOMPAT_FILETYPE | INCOMPAT_RECOVER | INCOMPAT_EXTENTS | INCOMPAT_64BIT | INCOMPAT_FLEX_BG;
+
+const MODE_TYPE_MASK: u16 = 0xf000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_REGULAR: u16 = 0x8000;
+
+const INODE_FLAG_EXTENTS: u32 = 0x8_0000;
+const INODE_FLAG_INLINE_DATA: u32 = 0x1000_0000;
+
+const EXTENT_MAGIC: u16 = 0xf30a;
+const EXTENT_ENTRY_SIZE: usize = 12;
+/// Extents longer than this are uninitialised, and read as zeroes.
+const EXTENT_MAX_INITIALISED_LEN: u16 = 32768;
+/// The maximum depth of an extent tree.
+const EXTENT_MAX_DEPTH: u16 = 5;
+
+/// The number of direct block pointers in an inode using block maps.
+const DIRECT_BLOCKS: u64 = 12;
+
+/// An ext2/ext4 partition.
+pub(crate) struct Volume<'a> {
+    disk: ScopedProtocol<'a, DiskIo>,
+    media_id: u32,
+    /// The byte offset of the partition from the start of the disk.
+    offset: u64,
+    block_size: u64,
+    inodes_count: u32,
+    inodes_per_group: u32,
+    inode_size: u64,
+    group_descriptor_size: u64,
+    /// The byte offset of the group descriptor table from the start of the
+    /// partition.
+    group_descriptors_offset: u64,
+    incompat: u32,
+}
+
+/// An inode, which describes a file or directory.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Inode {
+    mode: u16,
+    flags: u32,
+    size: u64,
+    /// The block map or the root of the extent tree.
+    block: [u8; 60],
+}
+
+impl Inode {
+    pub(crate) fn is_dir(&self) -> bool {
+        self.mode & MODE_TYPE_MASK == MODE_DIRECTORY
+    }
+
+    pub(crate) fn is_regular(&self) -> bool {
+        self.mode & MODE_TYPE_MASK == MODE_REGULAR
+    }
+
+    /// Returns the size of the file in bytes.
+    pub(crate) fn size(&self) -> usize {
+        self.size as usize
+    }
+}
+
+/// A node in an extent tree.
+enum ExtentNode {
+    /// The root node, which is stored in the inode.
+    Inode([u8; 60]),
+    /// A node stored in the block at the given byte offset from the start of
+    /// the partition.
+    Block(u64),
+}
+
+impl<'a> Volume<'a> {
+    /// Opens the partition at `offset` bytes from the start of the disk.
+    ///
+    /// Returns an error if the partition doesn't contain a supported ext2/ext4
+    /// file system.
+    pub(crate) fn new(
+        disk: ScopedProtocol<'a, DiskIo>,
+        media_id: u32,
+        offset: u64,
+    ) -> Result<Self, &'static str> {
+        let mut superblock = [0; SUPERBLOCK_SIZE];
+        disk.read_disk(media_id, offset + SUPERBLOCK_OFFSET, &mut superblock)
+            .map_err(|_| "failed to read ext2 superblock")?;
+
+        if u16_at(&superblock, 56) != SUPERBLOCK_MAGIC {
+            return Err("no ext2 file system found");
+        }
+
+        let incompat = u32_at(&superblock, 96);
+        if incompat & !SUPPORTED_INCOMPAT != 0 {
+            warn!(
+                "unsupported ext2 features: {:#x}",
+                incompat & !SUPPORTED_INCOMPAT
+            );
+            return Err("unsupported ext2 features");
+        }
+        if incompat & INCOMPAT_RECOVER != 0 {
+            warn!("ext2 journal needs recovery, files may be stale");
+        }
+
+        let log_block_size = u32_at(&superblock, 24);
+        if log_block_size > MAX_LOG_BLOCK_SIZE {
+            return Err("invalid ext2 block size");
+        }
+        let block_size = 1024 << log_block_size;
+
+        let inodes_per_group = u32_at(&superblock, 40);
+        if inodes_per_group == 0 {
+            return Err("invalid ext2 inodes per group");
+        }
+
+        // Revision 0 file systems have fixed size inodes.
+        let inode_size = if u32_at(&superblock, 76) == 0 {
+            128
+        } else {
+            u64::from(u16_at(&superblock, 88))
+        };
+        if inode_size < 128 || !inode_size.is_power_of_two() || inode_size > block_size {
+            return Err("invalid ext2 inode size");
+        }
+
+        let group_descriptor_size = if incompat & INCOMPAT_64BIT != 0 {
+            u64::from(u16_at(&superblock, 254))
+        } else {
+            32
+        };
+        if group_descriptor_size < 32
+            || !group_descriptor_size.is_power_of_two()
+            || group_descriptor_size > block_size
+        {
+            return Err("invalid ext2 group descriptor size");
+        }
+
+        let first_data_block = u64::from(u32_at(&superblock, 20));
+
+        Ok(Self {
+            disk,
+            media_id,
+            offset,
+            block_size,
+            inodes_count: u32_at(&superblock, 0),
+            inodes_per_group,
+            inode_size,
+            group_descriptor_size,
+            // The group descriptor table is in the block after the superblock.
+            group_descriptors_offset: (first_data_block + 1) * block_size,
+            incompat,
+        })
+    }
+
+    /// Reads `buffer.len()` bytes starting at `offset` bytes from the start of
+    /// the partition.
+    fn read_bytes(&self, offset: u64, buffer: &mut [u8]) -> Result<(), &'static str> {
+        self.disk
+            .read_disk(self.media_id, self.offset + offset, buffer)
+            .map_err(|_| "failed to read from ext2 partition")
+    }
+
+    fn read_u32(&self, offset: u64) -> Result<u32, &'static str> {
+        let mut buffer = [0; 4];
+        self.read_bytes(offset, &mut buffer)?;
+        Ok(u32::from_le_bytes(buffer))
+    }
+
+    fn inode(&self, number: u32) -> Result<Inode, &'static str> {
+        // Inode numbers start at one.
+        if number == 0 || number > self.inodes_count {
+            return Err("invalid ext2 inode number");
+        }
+        let group = u64::from((number - 1) / self.inodes_per_group);
+        let index = u64::from((number - 1) % self.inodes_per_group);
+
+        let mut descriptor = [0; 64];
+        let descriptor = &mut descriptor[..(self.group_descriptor_size as usize).min(64)];
+        self.read_bytes(
+            self.group_descriptors_offset + group * self.group_descriptor_size,
+            descriptor,
+        )?;
+        let mut inode_table = u64::from(u32_at(descriptor, 8));
+        if descriptor.len() >= 64 {
+            inode_table |= u64::from(u32_at(descriptor, 40)) << 32;
+        }
+
+        let mut raw = [0; 128];
+        self.read_bytes(
+            inode_table * self.block_size + index * self.inode_size,
+            &mut raw,
+        )?;
+
+        let mut block = [0; 60];
+        block.copy_from_slice(&raw[40..100]);
+        Ok(Inode {
+            mode: u16_at(&raw, 0),
+            flags: u32_at(&raw, 32),
+            size: u64::from(u32_at(&raw, 4)) | (u64::from(u32_at(&raw, 108)) << 32),
+            block,
+        })
+    }
+
+    /// Returns the inode of the file or directory at `path`, relative to the
+    /// root of the partition, or `None` if it doesn't exist.
+    pub(crate) fn open(&self, path: &str) -> Result<Option<Inode>, &'static str> {
+        let mut inode = self.inode(ROOT_INODE)?;
+        for component in path.split('/').filter(|component| !component.is_empty()) {
+            if !inode.is_dir() {
+                return Ok(None);
+            }
+            let number = match self.raw_entries(&inode, |name, number| {
+                if name == component.as_bytes() {
+                    ControlFlow::Break(number)
+                } else {
+                    ControlFlow::Continue(())
+                }
+            })? {
+                ControlFlow::Break(number) => number,
+                ControlFlow::Continue(()) => return Ok(None),
+            };
+            inode = self.inode(number)?;
+        }
+        Ok(Some(inode))
+    }
+
+    /// Calls `f` with the name and inode of every entry in `dir`, excluding
+    /// `.` and `..`.
+    ///
+    /// Stops at the first error returned by `f`.
+    pub(crate) fn for_each_entry(
+        &self,
+        dir: &Inode,
+        mut f: impl FnMut(&str, Inode) -> Result<(), &'static str>,
+    ) -> Result<(), &'static str> {
+        let flow = self.raw_entries(dir, |name, number| {
+            if name == b"." || name == b".." {
+                return ControlFlow::Continue(());
+            }
+            match core::str::from_utf8(name) {
+                Ok(name) => match self.inode(number).and_then(|inode| f(name, inode)) {
+                    Ok(()) => ControlFlow::Continue(()),
+                    Err(reason) => ControlFlow::Break(reason),
+                },
+                Err(_) => {
+                    warn!("skipping ext2 directory entry with invalid name: {name:?}");
+                    ControlFlow::Continue(())
+                }
+            }
+        })?;
+        match flow {
+            ControlFlow::Break(reason) => Err(reason),
+            ControlFlow::Continue(()) => Ok(()),
+        }
+    }
+
+    fn raw_entries<T>(
+        &self,
+        dir: &Inode,
+        mut f: impl FnMut(&[u8], u32) -> ControlFlow<T>,
+    ) -> Result<ControlFlow<T>, &'static str> {
+        let mut position = 0;
+        let mut header = [0; 8];
+        let mut name = [0; 255];
+
+        while position + header.len() as u64 <= dir.size {
+            self.read(dir, position, &mut header)?;
+            let number = u32_at(&header, 0);
+            let record_len = u64::from(u16_at(&header, 4));
+            // Without the file type feature, the name length is 16 bits.
+            let name_len = if self.incompat & INCOMPAT_FILETYPE != 0 {
+                usize::from(header[6])
+            } else {
+                usize::from(u16_at(&header, 6)).min(name.len())
+            };
+
+            if record_len == 0 {
+                warn!("ext2 directory entry at {position:#x} has zero length");
+                break;
+            }
+
+            // Unused entries have an inode number of zero.
+            if number != 0 {
+                let name = &mut name[..name_len];
+                self.read(dir, position + header.len() as u64, name)?;
+                if let ControlFlow::Break(value) = f(name, number) {
+                    return Ok(ControlFlow::Break(value));
+                }
+            }
+
+            position += record_len;
+        }
+
+        Ok(ControlFlow::Continue(()))
+    }
+
+    /// Reads bytes starting at `offset` in the file into `buffer`, returning
+    /// the number of bytes read.
+    ///
+    /// Fewer bytes than the length of `buffer` are read if the end of the file
+    /// is reached.
+    pub(crate) fn read(
+        &self,
+        inode: &Inode,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> Result<usize, &'static str> {
+        if inode.flags & INODE_FLAG_INLINE_DATA != 0 {
+            return Err("ext2 inline data isn't supported");
+        }
+
+        let len = (inode.size.saturating_sub(offset) as usize).min(buffer.len());
+        let mut done = 0;
+
+        while done < len {
+            let position = offset + done as u64;
+            let block = position / self.block_size;
+            let in_block_offset = position % self.block_size;
+            let chunk_len = ((self.block_size - in_block_offset) as usize).min(len - done);
+            let chunk = &mut buffer[done..(done + chunk_len)];
+
+            match self.block_number(inode, block)? {
+                Some(number) => {
+                    self.read_bytes(number * self.block_size + in_block_offset, chunk)?;
+                }
+                // Holes in sparse files are read as zeroes.
+                None => chunk.fill(0),
+            }
+
+            done += chunk_len;
+        }
+
+        Ok(len)
+    }
+
+    /// Returns the physical block number of the file's `block`th block, or
+    /// `None` if the block is a hole.
+    fn block_number(&self, inode: &Inode, block: u64) -> Result<Option<u64>, &'static str> {
+        if inode.flags & INODE_FLAG_EXTENTS != 0 {
+            self.extent_block_number(ExtentNode::Inode(inode.block), block, EXTENT_MAX_DEPTH)
+        } else {
+            self.mapped_block_number(inode, block)
+        }
+    }
+
+    /// Looks up `block` in the extent tree rooted at `node`, which must be at
+    /// most `max_depth` levels deep.
+    fn extent_block_number(
+        &self,
+        node: ExtentNode,
+        block: u64,
+        max_depth: u16,
+    ) -> Result<Option<u64>, &'static str> {
+        // The header is the same size as an entry, so it's treated as entry zero.
+        let entry = |index: usize| -> Result<[u8; EXTENT_ENTRY_SIZE], &'static str> {
+            let mut entry = [0; EXTENT_ENTRY_SIZE];
+            let offset = index * EXTENT_ENTRY_SIZE;
+            match node {
+                ExtentNode::Inode(ref bytes) => {
+                    entry.copy_from_slice(&bytes[offset..(offset + EXTENT_ENTRY_SIZE)]);
+                }
+                ExtentNode::Block(address) => {
+                    self.read_bytes(address + offset as u64, &mut entry)?;
+                }
+            }
+            Ok(entry)
+        };
+
+        let header = entry(0)?;
+        if u16_at(&header, 0) != EXTENT_MAGIC {
+            return Err("invalid ext2 extent header");
+        }
+        let num_entries = usize::from(u16_at(&header, 2));
+        let depth = u16_at(&header, 6);
+
+        let node_size = match node {
+            ExtentNode::Inode(ref bytes) => bytes.len(),
+            ExtentNode::Block(_) => self.block_size as usize,
+        };
+        if num_entries >= node_size / EXTENT_ENTRY_SIZE || depth > max_depth {
+            return Err("invalid ext2 extent header");
+        }
+
+        if depth == 0 {
+            for index in 1..=num_entries {
+                let extent = entry(index)?;
+                let start = u64::from(u32_at(&extent, 0));
+                let len = u16_at(&extent, 4);
+                let (len, is_initialised) = if len > EXTENT_MAX_INITIALISED_LEN {
+                    (len - EXTENT_MAX_INITIALISED_LEN, false)
+                } else {
+                    (len, true)
+                };
+                let physical_start =
+                    u64::from(u32_at(&extent, 8)) | (u64::from(u16_at(&extent, 6)) << 32);
+
+                if (start..(start + u64::from(len))).contains(&block) {
+                    // Uninitialised extents are treated like holes.
+                    return Ok(is_initialised.then_some(physical_start + block - start));
+                }
+            }
+            Ok(None)
+        } else {
+            // Index entries are sorted, so the child is the last one starting at or
+            // before the block.
+            let mut child = None;
+            for index in 1..=num_entries {
+                let entry = entry(index)?;
+                if u64::from(u32_at(&entry, 0)) > block {
+                    break;
+                }
+                child = Some(entry);
+            }
+            let child = match child {
+                Some(child) => child,
+                None => return Ok(None),
+            };
+            let child_block = u64::from(u32_at(&child, 4)) | (u64::from(u16_at(&child, 8)) << 32);
+            // Each level must be shallower than its parent, so corrupt trees can't
+            // loop forever.
+            self.extent_block_number(
+                ExtentNode::Block(child_block * self.block_size),
+                block,
+                depth - 1,
+            )
+        }
+    }
+
+    fn mapped_block_number(
+        &self,
+        inode: &Inode,
+        mut block: u64,
+    ) -> Result<Option<u64>, &'static str> {
+        let pointers_per_block = self.block_size / 4;
+
+        if block < DIRECT_BLOCKS {
+            return Ok(non_zero(u32_at(&inode.block, block as usize * 4)));
+        }
+        block -= DIRECT_BLOCKS;
+
+        // The number of data blocks covered by each of the singly, doubly, and triply
+        // indirect pointers.
+        let mut covered = pointers_per_block;
+        for level in 0..3 {
+            if block < covered {
+                let mut number =
+                    non_zero(u32_at(&inode.block, (DIRECT_BLOCKS as usize + level) * 4));
+                for _ in 0..=level {
+                    let current = match number {
+                        Some(number) => number,
+                        None => return Ok(None),
+                    };
+                    covered /= pointers_per_block;
+                    let index = block / covered;
+                    block %= covered;
+                    number = non_zero(self.read_u32(current * self.block_size + index * 4)?);
+                }
+                return Ok(number);
+            }
+            block -= covered;
+            covered *= pointers_per_block;
+        }
+
+        Ok(None)
+    }
+}
+
+fn non_zero(number: u32) -> Option<u64> {
+    (number != 0).then_some(u64::from(number))
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+impl BootContext {
+    /// Opens the ext2 partition specified in the configuration.
+    ///
+    /// Panics if no partition is specified.
+    pub(crate) fn open_ext2_volume(&self) -> Result<Volume<'_>, &'static str> {
+        let offset = self
+            .config
+            .ext2_offset
+            .expect("ext2_offset must be set to read from an ext2 partition");
+        // Partitions are also block devices, so only whole disks are considered.
+        let handles = firmware::find_handles::<BlockIO>(&self.system_table);
+        let (handle, media_id) = handles
+            .handles()
+            .iter()
+            .filter_map(|handle| {
+                // SAFETY: The block device is only used to query its media, which doesn't
+                // interfere with other users of the protocol.
+                let block_io = unsafe {
+                    firmware::open_protocol_shared::<BlockIO>(
+                        &self.system_table,
+                        *handle,
+                        self.image_handle,
+                    )
+                }?;
+                let media = block_io.media();
+                (media.is_media_present() && !media.is_logical_partition())
+                    .then_some((*handle, media.media_id()))
+            })
+            .nth(self.config.ext2_disk)
+            .ok_or("failed to find ext2 disk")?;
+
+        // SAFETY: The disk is only read, which doesn't interfere with other users of
+        // the protocol.
+        let disk = unsafe {
+            firmware::open_protocol_shared::<DiskIo>(&self.system_table, handle, self.image_handle)
+        }
+        .ok_or("failed to open ext2 disk")?;
+
+        Volume::new(disk, media_id, offset as u64)
+    }
+
+    /// Reads the file at `path` on the ext2 partition into newly allocated
+    /// pages.
+    pub(crate) fn read_ext2_file(&self, path: &str) -> &'static mut [u8] {
+        let volume = self
+            .open_ext2_volume()
+            .unwrap_or_else(|reason| panic!("failed to open ext2 partition: {reason}"));
+        let inode = volume
+            .open(path)
+            .unwrap_or_else(|reason| panic!("failed to open {path} on ext2 partition: {reason}"))
+            .filter(Inode::is_regular)
+            .unwrap_or_else(|| panic!("failed to find {path} on ext2 partition"));
+
+        let bytes = self.allocate_byte_slice(inode.size(), MemoryType::LOADER_DATA);
+        volume
+            .read(&inode, 0, bytes)
+            .unwrap_or_else(|reason| panic!("failed to read {path} on ext2 partition: {reason}"));
+        bytes
+    }
+}
@@ -0,0 +1,136 @@
+//! Wrappers around the boot services used by the bootloader.
+//!
+//! The bootloader targets UEFI 2.0 and later. Calls whose behaviour varies
+//! between spec revisions or `uefi` crate versions are centralised here so
+//! that adapting to those changes only touches this module.
+
+use log::{info, warn};
+use uefi::{
+    proto::{Protocol, ProtocolPointer},
+    table::{
+        boot::{
+            HandleBuffer, MemoryMapIter, OpenProtocolAttributes, OpenProtocolParams,
+            ScopedProtocol, SearchType,
+        },
+        Boot, SystemTable,
+    },
+    Handle,
+};
+
+/// Returns a handle that supports the protocol.
+pub(crate) fn find_handle<P>(system_table: &SystemTable<Boot>) -> Option<Handle>
+where
+    P: Protocol,
+{
+    system_table
+        .boot_services()
+        .get_handle_for_protocol::<P>()
+        .ok()
+}
+
+/// Returns all handles that support the protocol.
+pub(crate) fn find_handles<P>(system_table: &SystemTable<Boot>) -> HandleBuffer<'_>
+where
+    P: Protocol,
+{
+    system_table
+        .boot_services()
+        .locate_handle_buffer(SearchType::from_proto::<P>())
+        .expect("failed to locate handles")
+}
+
+/// Opens the protocol on the handle, preferring exclusive access.
+///
+/// Firmwares may keep protocols open (e.g. the graphics output protocol for
+/// the console), in which case exclusive access fails and the protocol is
+/// opened non-exclusively instead.
+pub(crate) fn open_protocol<P>(
+    system_table: &SystemTable<Boot>,
+    handle: Handle,
+    agent: Handle,
+) -> Option<ScopedProtocol<'_, P>>
+where
+    P: ProtocolPointer + ?Sized,
+{
+    let name = core::any::type_name::<P>();
+    match system_table
+        .boot_services()
+        .open_protocol_exclusive::<P>(handle)
+    {
+        Ok(protocol) => {
+            info!("opened {name} exclusively");
+            Some(protocol)
+        }
+        Err(error) => {
+            warn!("failed to open {name} exclusively: {:?}", error.status());
+            // SAFETY: The protocol is only used before exiting boot services, and the
+            // other agents using it don't close it.
+            let protocol = unsafe { open_protocol_shared::<P>(system_table, handle, agent) }?;
+            info!("opened {name} non-exclusively");
+            Some(protocol)
+        }
+    }
+}
+
+/// Opens the protocol on the handle without requesting exclusive access.
+///
+/// # Safety
+///
+/// The caller must ensure that using the protocol doesn't interfere with other
+/// agents using it, and that they don't close it while it's in use.
+pub(crate) unsafe fn open_protocol_shared<P>(
+    system_table: &SystemTable<Boot>,
+    handle: Handle,
+    agent: Handle,
+) -> Option<ScopedProtocol<'_, P>>
+where
+    P: ProtocolPointer + ?Sized,
+{
+    // SAFETY: Guaranteed by caller.
+    unsafe {
+        system_table.boot_services().open_protocol::<P>(
+            OpenProtocolParams {
+                handle,
+                agent,
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+    }
+    .ok()
+}
+
+/// Returns a buffer size large enough to hold the current memory map.
+///
+/// The map can grow when the buffer itself is allocated, so room for a few
+/// extra descriptors is included.
+pub(crate) fn memory_map_size(system_table: &SystemTable<Boot>) -> usize {
+    let size = system_table.boot_services().memory_map_size();
+    size.map_size + 8 * size.entry_size
+}
+
+/// Returns the current memory map, stored in `buffer`.
+pub(crate) fn memory_map<'a>(
+    system_table: &SystemTable<Boot>,
+    buffer: &'a mut [u8],
+) -> MemoryMapIter<'a> {
+    let (_, memory_map) = system_table
+        .boot_services()
+        .memory_map(buffer)
+        .expect("failed to get memory map");
+    memory_map
+}
+
+/// Exits boot services, returning the final memory map.
+///
+/// The memory map key can change between fetching the memory map and exiting
+/// boot services (e.g. if an event allocates memory), so the `uefi` crate
+/// retries with a fresh memory map if the first attempt fails.
+///
+/// The returned iterator advances by the descriptor size reported by the
+/// firmware rather than `size_of::<MemoryDescriptor>()`, so firmwares with
+/// extended descriptors are handled correctly.
+pub(crate) fn exit_boot_services(system_table: SystemTable<Boot>) -> MemoryMapIter<'static> {
+    let (_, memory_map) = system_table.exit_boot_services();
+    memory_map
+}
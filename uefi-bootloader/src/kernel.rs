@@ -1,84 +1,191 @@
-use crate::{memory::VirtualAddress, BootContext};
+use crate::{
+    config::{is_valid_stack_size, HigherHalf},
+    memory::{VirtualAddress, DEVICE_TREE_MEMORY, KERNEL_MEMORY},
+    modules::MODULES_MEMORY,
+    source::Source,
+    util::crc32,
+    BootContext,
+};
 use core::mem::MaybeUninit;
 use goblin::elf64::{
-    header::Header,
+    header::{Header, ET_DYN},
     program_header::{ProgramHeader, SIZEOF_PHDR},
     section_header::{SectionHeader, SIZEOF_SHDR},
 };
-use log::info;
+use log::{error, info, warn};
 use plain::Plain;
-use uefi::{
-    prelude::cstr16,
-    proto::media::file::{File, FileAttribute, FileMode, FileType, RegularFile},
-    table::boot::MemoryType,
-    CStr16,
+use uefi::table::boot::MemoryType;
+use uefi_bootloader_api::{
+    ElfSection, KernelMemoryNote, KernelSegmentHashNote, KernelStackNote, KernelVersionNote,
+    KERNEL_MEMORY_NOTE_TYPE, KERNEL_SEGMENT_HASH_NOTE_TYPE, KERNEL_STACK_NOTE_TYPE,
+    KERNEL_VERSION_NOTE_TYPE, NOTE_NAME,
 };
-use uefi_bootloader_api::ElfSection;
 
-const KERNEL_NAME: &CStr16 = cstr16!("kernel.elf");
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+
+const NOTE_HEADER_SIZE: u64 = 12;
+
+/// The number of segments that can have a hash note.
+const MAX_HASHED_SEGMENTS: usize = 64;
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "embedded-kernel")] {
+        /// The kernel, embedded from the path in the `UEFI_BOOTLOADER_KERNEL`
+        /// environment variable at compile time.
+        static EMBEDDED_KERNEL: &[u8] = include_bytes!(env!("UEFI_BOOTLOADER_KERNEL"));
+    } else {
+        use crate::util::{to_uefi_path, MAX_PATH_LEN};
+        use uefi::proto::media::file::{File, FileAttribute, FileMode, FileType, RegularFile};
+    }
+}
 
 impl BootContext {
     pub(crate) fn load_kernel(&mut self) -> (VirtualAddress, &'static mut [ElfSection]) {
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "embedded-kernel")] {
+                self.load_kernel_from(EMBEDDED_KERNEL)
+            } else {
+                if self.config.kernel_in_memory {
+                    let bytes = self.in_memory_kernel();
+                    return self.load_kernel_from(bytes);
+                }
+
+                if let Some(path) = self.config.kernel_manifest {
+                    return self.load_kernel_from_manifest(path);
+                }
+
+                match self.config.ext2_kernel {
+                    Some(path) => {
+                        let bytes = self.read_ext2_file(path);
+                        self.load_kernel_from(&*bytes)
+                    }
+                    None => {
+                        let file = self.open_kernel_file();
+                        self.load_kernel_from(file)
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(not(feature = "embedded-kernel"))]
+    fn open_kernel_file(&self) -> RegularFile {
+        let mut path_buf = [0; MAX_PATH_LEN];
+        let path = to_uefi_path(self.config.kernel, &mut path_buf);
+
         let mut root = self
             .open_file_system_root()
             .expect("failed to open file system root");
 
-        let file = match root
-            .open(KERNEL_NAME, FileMode::Read, FileAttribute::empty())
+        match root
+            .open(path, FileMode::Read, FileAttribute::empty())
             .expect("failed to open kernel file")
             .into_type()
             .expect("kernel file was closed or deleted")
         {
             FileType::Regular(file) => file,
             FileType::Dir(_) => panic!(),
-        };
+        }
+    }
+
+    fn load_kernel_from<S>(&mut self, mut source: S) -> (VirtualAddress, &'static mut [ElfSection])
+    where
+        S: Source,
+    {
+        let len = source.size().expect("failed to get kernel length");
 
         Loader {
-            file,
+            source,
+            len,
             context: self,
+            segment_hashes: [None; MAX_HASHED_SEGMENTS],
         }
         .load()
     }
 }
 
-struct Loader<'a> {
-    file: RegularFile,
+/// Panics if the segment's virtual addresses are non-canonical or if its
+/// virtual address isn't congruent to its file offset modulo its alignment.
+///
+/// This must be checked on the raw values as [`VirtualAddress::new_canonical`]
+/// would silently mask non-canonical addresses.
+pub(crate) fn validate_load_segment(index: u64, segment: &ProgramHeader) {
+    let start = segment.p_vaddr;
+    let end_inclusive = start
+        .checked_add(segment.p_memsz - 1)
+        .unwrap_or_else(|| panic!("segment {index} overflows the address space: {segment:x?}"));
+
+    for address in [start, end_inclusive] {
+        assert!(
+            VirtualAddress::new(address as usize).is_some(),
+            "segment {index} has non-canonical virtual address {address:#x}: {segment:x?}"
+        );
+    }
+
+    // An alignment of zero or one means no alignment is required.
+    if segment.p_align > 1 {
+        assert!(
+            start.wrapping_sub(segment.p_offset) % segment.p_align == 0,
+            "segment {index} virtual address {start:#x} isn't congruent to file offset {:#x} \
+             modulo alignment {:#x}",
+            segment.p_offset,
+            segment.p_align
+        );
+    }
+}
+
+struct Loader<'a, S> {
+    source: S,
+    /// The length of the kernel in bytes.
+    len: u64,
     context: &'a mut BootContext,
+    /// The expected CRC-32 of each segment's file contents, indexed by program
+    /// header.
+    segment_hashes: [Option<u32>; MAX_HASHED_SEGMENTS],
 }
 
-impl Loader<'_> {
+impl<S> Loader<'_, S>
+where
+    S: Source,
+{
     fn load(mut self) -> (VirtualAddress, &'static mut [ElfSection]) {
         let mut buffer = [0; core::mem::size_of::<Header>()];
-        self.file
-            .read(&mut buffer)
+        self.check_len(0, buffer.len() as u64);
+        self.source
+            .read_at(0, &mut buffer)
             .expect("failed to read kernel header");
 
         let kernel_header = Header::from_bytes(&buffer);
+        // No load bias is applied, so position-independent kernels are loaded at their
+        // linked addresses too.
+        if kernel_header.e_type == ET_DYN {
+            info!("loading position-independent kernel at its linked address");
+        }
 
-        let program_header_offset = kernel_header.e_phoff;
         let program_header_count = kernel_header.e_phnum;
 
-        let mut buffer = [0; SIZEOF_PHDR];
-
+        // Notes are handled first so that segment hashes are known before the
+        // segments are loaded.
         for i in 0..program_header_count.into() {
-            // Loading segments modifies the file position.
-            self.file
-                .set_position(program_header_offset + (i * SIZEOF_PHDR as u64))
-                .expect("failed to set kernel file position to program header");
-            self.file
-                .read(&mut buffer)
-                .expect("failed to read kernel program header");
+            let program_header = self.program_header(kernel_header, i);
+            if program_header.p_type == PT_NOTE {
+                self.handle_note_segment(&program_header);
+            }
+        }
 
-            let program_header = ProgramHeader::from_bytes(&buffer)
-                .expect("failed to create program header from bytes");
+        for i in 0..program_header_count.into() {
+            let program_header = self.program_header(kernel_header, i);
 
             // .got section
             if program_header.p_memsz == 0 {
                 continue;
             }
 
-            if program_header.p_type == 1 {
-                self.handle_load_segment(program_header);
+            if program_header.p_type == PT_LOAD {
+                validate_load_segment(i, &program_header);
+                self.check_higher_half(i, &program_header);
+                self.handle_load_segment(i, &program_header);
             }
         }
 
@@ -88,6 +195,17 @@ impl Loader<'_> {
         )
     }
 
+    fn program_header(&mut self, header: &Header, index: u64) -> ProgramHeader {
+        let mut buffer = [0; SIZEOF_PHDR];
+        let position = header.e_phoff + (index * SIZEOF_PHDR as u64);
+        self.check_len(position, SIZEOF_PHDR as u64);
+        self.source
+            .read_at(position, &mut buffer)
+            .expect("failed to read kernel program header");
+
+        *ProgramHeader::from_bytes(&buffer).expect("failed to create program header from bytes")
+    }
+
     fn elf_sections(&mut self, header: &Header) -> &'static mut [ElfSection] {
         let program_header_count = header.e_shnum;
 
@@ -99,33 +217,29 @@ impl Loader<'_> {
         let mut buffer = [0; SIZEOF_SHDR];
 
         let shstrtab_header = header.e_shoff + (u64::from(header.e_shstrndx) * SIZEOF_SHDR as u64);
-        self.file
-            .set_position(shstrtab_header)
-            .expect("failed to set kernel file position to shstrtab header");
-        self.file
-            .read(&mut buffer)
+        self.check_len(shstrtab_header, SIZEOF_SHDR as u64);
+        self.source
+            .read_at(shstrtab_header, &mut buffer)
             .expect("failed to read kernel shstrtab header");
         let shstrtab_section_header =
             SectionHeader::from_bytes(&buffer).expect("failed to create section header from bytes");
         let shstrtab_base = shstrtab_section_header.sh_offset;
 
         for (i, uninit_section) in sections.iter_mut().enumerate() {
-            self.file
-                .set_position(header.e_shoff + (i * SIZEOF_SHDR) as u64)
-                .expect("failed to set kernel file position to section header");
-            self.file
-                .read(&mut buffer)
+            let position = header.e_shoff + (i * SIZEOF_SHDR) as u64;
+            self.check_len(position, SIZEOF_SHDR as u64);
+            self.source
+                .read_at(position, &mut buffer)
                 .expect("failed to read kernel section header");
             let section_header = SectionHeader::from_bytes(&buffer)
                 .expect("failed to create section header from bytes");
 
             let mut name = [0; 64];
             let name_position = shstrtab_base + u64::from(section_header.sh_name);
-            self.file
-                .set_position(name_position)
-                .expect("failed to set kernel file position to shstrab name position");
-            self.file
-                .read(&mut name)
+            // The name is null-terminated, so we only require the first byte to be present.
+            self.check_len(name_position, 1);
+            self.source
+                .read_at(name_position, &mut name)
                 .expect("failed to read kernel section name");
 
             uninit_section.write(ElfSection {
@@ -133,6 +247,7 @@ impl Loader<'_> {
                 start: section_header.sh_addr as usize,
                 size: section_header.sh_size as usize,
                 flags: section_header.sh_flags,
+                align: section_header.sh_addralign as usize,
             });
         }
 
@@ -140,18 +255,271 @@ impl Loader<'_> {
         unsafe { MaybeUninit::slice_assume_init_mut(sections) }
     }
 
-    fn handle_load_segment(&mut self, segment: &ProgramHeader) {
+    fn handle_load_segment(&mut self, index: u64, segment: &ProgramHeader) {
         info!("loading segment: {segment:?}");
         let slice = self.context.map_segment(segment);
         info!("at paddr: {:x?}", slice.as_ptr());
 
-        self.file
-            .set_position(segment.p_offset)
-            .expect("failed to set kernel file position to segment offset");
-        self.file
-            .read(&mut slice[..segment.p_filesz as usize])
-            .expect("failed to read kernel segment");
+        // A pure BSS segment has no file contents, and its file offset may not be
+        // meaningful, so there's nothing to read.
+        if segment.p_filesz > 0 {
+            self.check_len(segment.p_offset, segment.p_filesz);
+            self.source
+                .read_at(segment.p_offset, &mut slice[..segment.p_filesz as usize])
+                .expect("failed to read kernel segment");
+        }
+
+        if let Some(expected) = self.segment_hashes.get(index as usize).copied().flatten() {
+            let actual = crc32(&slice[..segment.p_filesz as usize]);
+            assert!(
+                actual == expected,
+                "segment {index} has CRC-32 {actual:#010x}, but the kernel expected \
+                 {expected:#010x}"
+            );
+            info!("verified segment {index}");
+        }
+
+        // The BSS section was already zeroed by `map_segment`, which maps and
+        // zeroes the full `p_memsz` bytes. The self-test checks this, including for
+        // pure BSS segments.
+        if self.context.config.selftest {
+            let bss = &slice[segment.p_filesz as usize..segment.p_memsz as usize];
+            if bss.iter().all(|byte| *byte == 0) {
+                info!(
+                    "BSS self-test PASS: segment {index} has {:#x} zeroed bytes",
+                    bss.len()
+                );
+            } else {
+                error!("BSS self-test FAIL: segment {index} has non-zero bytes after its contents");
+            }
+        }
+    }
+
+    /// Warns about or rejects a segment in the lower half of the address space,
+    /// depending on the `higher_half` configuration.
+    fn check_higher_half(&self, index: u64, segment: &ProgramHeader) {
+        // The segment was validated, so its end doesn't overflow.
+        let start = segment.p_vaddr;
+        let end = start + segment.p_memsz;
+        if start >> 63 == 1 {
+            return;
+        }
+
+        match self.context.config.higher_half {
+            HigherHalf::Off => {}
+            HigherHalf::Warn => warn!(
+                "segment {index} at {start:#x}..{end:#x} is in the lower half, check the kernel's \
+                 linker script"
+            ),
+            HigherHalf::Require => panic!(
+                "segment {index} at {start:#x}..{end:#x} is in the lower half, but a higher-half \
+                 kernel is required"
+            ),
+        }
+    }
+
+    fn handle_note_segment(&mut self, segment: &ProgramHeader) {
+        // Notes are padded to the segment's alignment, which is at least four bytes.
+        let align = segment.p_align.max(4);
+        let pad = |len: u64| (len + align - 1) & !(align - 1);
+
+        self.check_len(segment.p_offset, segment.p_filesz);
+        let end = segment.p_offset + segment.p_filesz;
+        let mut position = segment.p_offset;
+
+        while position + NOTE_HEADER_SIZE <= end {
+            let mut header = [0; NOTE_HEADER_SIZE as usize];
+            self.source
+                .read_at(position, &mut header)
+                .expect("failed to read kernel note header");
+            let name_size = u64::from(u32_at(&header, 0));
+            let desc_size = u64::from(u32_at(&header, 4));
+            let ty = u32_at(&header, 8);
+
+            let name_position = position + NOTE_HEADER_SIZE;
+            let desc_position = name_position + pad(name_size);
+            position = desc_position + pad(desc_size);
+
+            // The name is null-terminated.
+            if name_size != NOTE_NAME.len() as u64 + 1 {
+                continue;
+            }
+            let mut name = [0; NOTE_NAME.len()];
+            self.check_len(name_position, name_size);
+            self.source
+                .read_at(name_position, &mut name)
+                .expect("failed to read kernel note name");
+            if name != NOTE_NAME.as_bytes() {
+                continue;
+            }
+
+            match ty {
+                KERNEL_STACK_NOTE_TYPE => {
+                    let desc = self.read_note_desc::<{ core::mem::size_of::<KernelStackNote>() }>(
+                        desc_position,
+                        desc_size,
+                        "stack",
+                    );
+                    self.handle_stack_note(&desc);
+                }
+                KERNEL_VERSION_NOTE_TYPE => {
+                    let desc = self
+                        .read_note_desc::<{ core::mem::size_of::<KernelVersionNote>() }>(
+                            desc_position,
+                            desc_size,
+                            "version",
+                        );
+                    handle_version_note(&desc);
+                }
+                KERNEL_MEMORY_NOTE_TYPE => {
+                    let desc = self.read_note_desc::<{ core::mem::size_of::<KernelMemoryNote>() }>(
+                        desc_position,
+                        desc_size,
+                        "memory",
+                    );
+                    self.handle_memory_note(&desc);
+                }
+                KERNEL_SEGMENT_HASH_NOTE_TYPE => {
+                    let desc = self
+                        .read_note_desc::<{ core::mem::size_of::<KernelSegmentHashNote>() }>(
+                            desc_position,
+                            desc_size,
+                            "segment hash",
+                        );
+                    self.handle_segment_hash_note(&desc);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn read_note_desc<const N: usize>(&mut self, position: u64, size: u64, name: &str) -> [u8; N] {
+        assert_eq!(size, N as u64, "kernel {name} note has an invalid size");
+        let mut desc = [0; N];
+        self.check_len(position, size);
+        self.source
+            .read_at(position, &mut desc)
+            .unwrap_or_else(|_| panic!("failed to read kernel {name} note"));
+        desc
+    }
+
+    fn handle_stack_note(&mut self, desc: &[u8]) {
+        let size = u64_at(desc, 0) as usize;
+        let align = u64_at(desc, 8) as usize;
+        info!("kernel requested a {size:#x} byte stack aligned to {align:#x}");
 
-        // The BSS section was already zeroed by `map_segment`.
+        assert!(
+            is_valid_stack_size(size),
+            "kernel requested an invalid stack size: {size:#x}"
+        );
+        self.context.config.stack_size = size;
+
+        if align != 0 {
+            assert!(
+                align.is_power_of_two(),
+                "kernel requested an invalid stack alignment: {align:#x}"
+            );
+            self.context.config.stack_align = align;
+        }
+    }
+
+    fn handle_memory_note(&mut self, desc: &[u8]) {
+        let ty = u64_at(desc, 0);
+        let size = u64_at(desc, 8) as usize;
+
+        let ty = u32::try_from(ty)
+            .ok()
+            .filter(|ty| *ty >= 0x7000_0000)
+            .map(MemoryType::custom)
+            .filter(|ty| ![KERNEL_MEMORY, MODULES_MEMORY, DEVICE_TREE_MEMORY].contains(ty))
+            .unwrap_or_else(|| panic!("kernel requested an invalid memory type: {ty:#x}"));
+        assert!(size > 0, "kernel requested an empty allocation");
+
+        let bytes = self.context.allocate_byte_slice(size, ty);
+        info!(
+            "allocated {size:#x} bytes of memory type {:#x} at {:#x} for kernel",
+            ty.0,
+            bytes.as_ptr() as usize
+        );
+    }
+
+    fn handle_segment_hash_note(&mut self, desc: &[u8]) {
+        let index = u32_at(desc, 0) as usize;
+        let crc32 = u32_at(desc, 4);
+
+        let hash = self.segment_hashes.get_mut(index).unwrap_or_else(|| {
+            panic!(
+                "kernel provided a hash for segment {index}, but only the first \
+                 {MAX_HASHED_SEGMENTS} segments can be verified"
+            )
+        });
+        assert!(
+            hash.is_none(),
+            "kernel provided multiple hashes for segment {index}"
+        );
+        *hash = Some(crc32);
+    }
+
+    /// Panics if the kernel file doesn't contain `size` bytes starting at
+    /// `offset`.
+    ///
+    /// This catches kernel files that are empty or were truncated (e.g. by an
+    /// interrupted copy) before we read out of bounds.
+    fn check_len(&self, offset: u64, size: u64) {
+        let expected = offset
+            .checked_add(size)
+            .expect("kernel file offset overflowed");
+        assert!(
+            expected <= self.len,
+            "kernel file truncated: expected >= {expected} bytes, got {}",
+            self.len
+        );
     }
 }
+
+fn handle_version_note(desc: &[u8]) {
+    let required = (u32_at(desc, 0), u32_at(desc, 4), u32_at(desc, 8));
+    let (major, minor, patch) = required;
+    info!("kernel requires bootloader version {major}.{minor}.{patch}");
+
+    let parse = |part: &str| {
+        part.parse::<u32>()
+            .expect("failed to parse bootloader version")
+    };
+    let current = (
+        parse(env!("CARGO_PKG_VERSION_MAJOR")),
+        parse(env!("CARGO_PKG_VERSION_MINOR")),
+        parse(env!("CARGO_PKG_VERSION_PATCH")),
+    );
+    assert!(
+        is_compatible_version(current, required),
+        "kernel requires bootloader version ^{major}.{minor}.{patch}, but this is version {}",
+        env!("CARGO_PKG_VERSION")
+    );
+}
+
+/// Returns whether `current` satisfies the semver requirement
+/// `^required`.
+///
+/// The leftmost non-zero component must match, and `current` mustn't be older
+/// than `required`.
+fn is_compatible_version(current: (u32, u32, u32), required: (u32, u32, u32)) -> bool {
+    let same_series = match required {
+        (0, 0, _) => current == required,
+        (0, minor, _) => current.0 == 0 && current.1 == minor,
+        (major, _, _) => current.0 == major,
+    };
+    same_series && current >= required
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    let mut buffer = [0; 4];
+    buffer.copy_from_slice(&bytes[offset..(offset + 4)]);
+    u32::from_le_bytes(buffer)
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    let mut buffer = [0; 8];
+    buffer.copy_from_slice(&bytes[offset..(offset + 8)]);
+    u64::from_le_bytes(buffer)
+}
@@ -1,19 +1,35 @@
 use core::{
     fmt::{self, Write},
     ptr,
+    sync::atomic::{AtomicBool, Ordering},
 };
 use font_constants::BACKUP_CHAR;
 use noto_sans_mono_bitmap::{
     get_raster, get_raster_width, FontWeight, RasterHeight, RasterizedChar,
 };
 use spin::{Mutex, Once};
+use uefi::proto::{
+    console::text::Output,
+    media::file::{File, RegularFile},
+};
 use uefi_bootloader_api::{FrameBufferInfo, PixelFormat};
 
 /// The global logger instance used for the `log` crate.
 pub(crate) static LOGGER: Once<LockedLogger> = Once::new();
 
-/// A [`Logger`] instance protected by a spinlock.
-pub(crate) struct LockedLogger(Mutex<Logger>);
+/// The logger sinks, each protected by a spinlock.
+///
+/// Until a framebuffer logger is set, records are written to the UEFI console
+/// while it's available.
+///
+/// The console and framebuffer are only cleared when the first record is
+/// written to them, so a quiet boot leaves the screen untouched unless an
+/// error occurs.
+pub(crate) struct LockedLogger {
+    frame_buffer: Mutex<Option<Logger>>,
+    file: Mutex<Option<FileLogger>>,
+    console_cleared: AtomicBool,
+}
 
 /// Additional vertical space between lines
 const LINE_SPACING: usize = 2;
@@ -57,9 +73,34 @@ fn get_char_raster(c: char) -> RasterizedChar {
 }
 
 impl LockedLogger {
-    /// Create a new instance that logs to the given framebuffer.
-    pub(crate) fn new(framebuffer: &'static mut [u8], info: FrameBufferInfo) -> Self {
-        LockedLogger(Mutex::new(Logger::new(framebuffer, info)))
+    /// Create a new instance that logs to the UEFI console.
+    pub(crate) fn new() -> Self {
+        LockedLogger {
+            frame_buffer: Mutex::new(None),
+            file: Mutex::new(None),
+            console_cleared: AtomicBool::new(false),
+        }
+    }
+
+    /// Starts writing log records to the given framebuffer logger rather than
+    /// the UEFI console.
+    pub(crate) fn set_frame_buffer(&self, logger: Logger) {
+        *self.frame_buffer.lock() = Some(logger);
+    }
+
+    /// Starts appending log records to the given file.
+    pub(crate) fn set_file(&self, file: RegularFile) {
+        *self.file.lock() = Some(FileLogger::new(file));
+    }
+
+    /// Flushes and closes the log file.
+    ///
+    /// This must be called before exiting boot services, after which the file
+    /// can no longer be written to.
+    pub(crate) fn close_file(&self) {
+        if let Some(mut file) = self.file.lock().take() {
+            file.flush();
+        }
     }
 
     /// Force-unlocks the logger to prevent a deadlock.
@@ -70,7 +111,9 @@ impl LockedLogger {
     /// underlying logger.
     pub(crate) unsafe fn force_unlock(&self) {
         // SAFETY: Guaranteed by caller.
-        unsafe { self.0.force_unlock() };
+        unsafe { self.frame_buffer.force_unlock() };
+        // SAFETY: Guaranteed by caller.
+        unsafe { self.file.force_unlock() };
     }
 }
 
@@ -80,11 +123,104 @@ impl log::Log for LockedLogger {
     }
 
     fn log(&self, record: &log::Record<'_>) {
-        let mut logger = self.0.lock();
-        writeln!(logger, "{:5}: {}", record.level(), record.args()).unwrap();
+        match self.frame_buffer.lock().as_mut() {
+            Some(logger) => {
+                writeln!(logger, "{:5}: {}", record.level(), record.args()).unwrap();
+            }
+            None => log_to_console(record, &self.console_cleared),
+        }
+        if let Some(file) = self.file.lock().as_mut() {
+            // There's nowhere to report a failure to write to the log file.
+            let _ = writeln!(file, "{:5}: {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = self.file.lock().as_mut() {
+            file.flush();
+        }
+    }
+}
+
+/// Writes the record to the UEFI console, if boot services are still active.
+///
+/// Before the first record is written, the console is switched to the largest
+/// text mode and cleared.
+fn log_to_console(record: &log::Record<'_>, cleared: &AtomicBool) {
+    // SAFETY: We are the sole thread.
+    if let Some(mut system_table_pointer) = unsafe { crate::SYSTEM_TABLE } {
+        // SAFETY: We are the sole thread.
+        let system_table = unsafe { system_table_pointer.as_mut() };
+        if !cleared.swap(true, Ordering::Relaxed) {
+            set_largest_text_mode(system_table.stdout());
+            let _ = system_table.stdout().clear();
+        }
+        // There's nowhere to report a failure to write to the console.
+        let _ = writeln!(
+            system_table.stdout(),
+            "{:5}: {}",
+            record.level(),
+            record.args()
+        );
+    }
+}
+
+/// Sets the text mode with the most rows and columns, so that as much as
+/// possible is visible before the framebuffer logger is set up.
+///
+/// The current mode is kept if setting the mode fails.
+fn set_largest_text_mode(stdout: &mut Output) {
+    let mode = stdout
+        .modes()
+        .max_by_key(|mode| (mode.rows() * mode.columns(), mode.rows()));
+    if let Some(mode) = mode {
+        // There's nowhere to report a failure to set the mode.
+        let _ = stdout.set_mode(mode);
+    }
+}
+
+/// Buffers log records and appends them to a file.
+struct FileLogger {
+    file: RegularFile,
+    buffer: [u8; 4096],
+    len: usize,
+}
+
+impl FileLogger {
+    fn new(file: RegularFile) -> Self {
+        Self {
+            file,
+            buffer: [0; 4096],
+            len: 0,
+        }
     }
 
-    fn flush(&self) {}
+    /// Writes the buffered records to the file.
+    fn flush(&mut self) {
+        let _ = self.file.write(&self.buffer[..self.len]);
+        let _ = self.file.flush();
+        self.len = 0;
+    }
+}
+
+// SAFETY: 🤷
+unsafe impl Send for FileLogger {}
+
+impl Write for FileLogger {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let bytes = s.as_bytes();
+        if self.len + bytes.len() > self.buffer.len() {
+            self.flush();
+        }
+
+        if bytes.len() > self.buffer.len() {
+            self.file.write(bytes).map_err(|_| fmt::Error)
+        } else {
+            self.buffer[self.len..(self.len + bytes.len())].copy_from_slice(bytes);
+            self.len += bytes.len();
+            Ok(())
+        }
+    }
 }
 
 /// Allows logging text to a pixel-based framebuffer.
@@ -93,24 +229,64 @@ pub(crate) struct Logger {
     info: FrameBufferInfo,
     x_pos: usize,
     y_pos: usize,
+    cleared: bool,
+    /// Whether the screen is cleared before the first character is written
+    /// and when it's full.
+    clear_screen: bool,
+    /// Whether the logger has wrapped back to the top of an uncleared screen.
+    wrapped: bool,
 }
 
 impl Logger {
     /// Creates a new logger that uses the given framebuffer.
-    pub(crate) fn new(framebuffer: &'static mut [u8], info: FrameBufferInfo) -> Self {
-        let mut logger = Self {
+    ///
+    /// If `clear_screen` is set, the framebuffer is cleared when the first
+    /// character is written. Otherwise, text is written over the existing
+    /// contents (e.g. a firmware splash). Glyphs are drawn on a black
+    /// background, so the text stays readable.
+    pub(crate) fn new(
+        framebuffer: &'static mut [u8],
+        info: FrameBufferInfo,
+        clear_screen: bool,
+    ) -> Self {
+        Self {
             framebuffer,
             info,
-            x_pos: 0,
-            y_pos: 0,
-        };
-        logger.clear();
-        logger
+            x_pos: BORDER_PADDING,
+            y_pos: BORDER_PADDING,
+            cleared: false,
+            clear_screen,
+            wrapped: false,
+        }
     }
 
     fn newline(&mut self) {
         self.y_pos += font_constants::CHAR_RASTER_HEIGHT.val() + LINE_SPACING;
         self.carriage_return();
+        if self.wrapped {
+            self.erase_line();
+        }
+    }
+
+    /// Moves back to the top of the screen without clearing it.
+    ///
+    /// Each line is erased before it's written to, so that the new text isn't
+    /// mixed with the old text.
+    fn wrap(&mut self) {
+        self.x_pos = BORDER_PADDING;
+        self.y_pos = BORDER_PADDING;
+        self.wrapped = true;
+        self.erase_line();
+    }
+
+    /// Erases the line starting at `self.y_pos`.
+    fn erase_line(&mut self) {
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+        let line_height = font_constants::CHAR_RASTER_HEIGHT.val() + LINE_SPACING;
+        for y in self.y_pos..(self.y_pos + line_height).min(self.height()) {
+            let start = y * self.info.pitch_bytes;
+            self.framebuffer[start..(start + self.width() * bytes_per_pixel)].fill(0);
+        }
     }
 
     fn carriage_return(&mut self) {
@@ -122,6 +298,7 @@ impl Logger {
         self.x_pos = BORDER_PADDING;
         self.y_pos = BORDER_PADDING;
         self.framebuffer.fill(0);
+        self.cleared = true;
     }
 
     fn width(&self) -> usize {
@@ -147,7 +324,11 @@ impl Logger {
                 let new_ypos =
                     self.y_pos + font_constants::CHAR_RASTER_HEIGHT.val() + BORDER_PADDING;
                 if new_ypos >= self.height() {
-                    self.clear();
+                    if self.clear_screen {
+                        self.clear();
+                    } else {
+                        self.wrap();
+                    }
                 }
                 self.write_rendered_char(&get_char_raster(c));
             }
@@ -165,14 +346,50 @@ impl Logger {
         self.x_pos += rendered_char.width() + LETTER_SPACING;
     }
 
+    /// Draws colour bars surrounded by a 1px white border.
+    ///
+    /// The bars are, from left to right, white, yellow, cyan, green, magenta,
+    /// red, blue, and black. Skewed bars indicate an incorrect stride or bytes
+    /// per pixel, and swapped colours indicate an incorrect pixel format.
+    pub(crate) fn draw_test_pattern(&mut self) {
+        const BARS: [[u8; 3]; 8] = [
+            [0xff, 0xff, 0xff],
+            [0xff, 0xff, 0],
+            [0, 0xff, 0xff],
+            [0, 0xff, 0],
+            [0xff, 0, 0xff],
+            [0xff, 0, 0],
+            [0, 0, 0xff],
+            [0, 0, 0],
+        ];
+
+        let width = self.width();
+        let height = self.height();
+        for y in 0..height {
+            for x in 0..width {
+                let color = if x == 0 || y == 0 || x == width - 1 || y == height - 1 {
+                    BARS[0]
+                } else {
+                    BARS[x * BARS.len() / width]
+                };
+                self.write_color(x, y, color);
+            }
+        }
+        self.cleared = false;
+    }
+
     fn write_pixel(&mut self, x: usize, y: usize, intensity: u8) {
-        let pixel_offset = y * self.info.stride + x;
+        self.write_color(x, y, [intensity, intensity, intensity / 2]);
+    }
+
+    /// Writes the given red, green, and blue components to the pixel.
+    fn write_color(&mut self, x: usize, y: usize, [red, green, blue]: [u8; 3]) {
         let color = match self.info.pixel_format {
-            PixelFormat::Rgb => [intensity, intensity, intensity / 2, 0],
-            PixelFormat::Bgr => [intensity / 2, intensity, intensity, 0],
+            PixelFormat::Rgb => [red, green, blue, 0],
+            PixelFormat::Bgr => [blue, green, red, 0],
         };
         let bytes_per_pixel = self.info.bytes_per_pixel;
-        let byte_offset = pixel_offset * bytes_per_pixel;
+        let byte_offset = y * self.info.pitch_bytes + x * bytes_per_pixel;
         self.framebuffer[byte_offset..(byte_offset + bytes_per_pixel)]
             .copy_from_slice(&color[..bytes_per_pixel]);
         // SAFETY: The frame buffer is valid.
@@ -187,6 +404,9 @@ unsafe impl Sync for Logger {}
 
 impl Write for Logger {
     fn write_str(&mut self, s: &str) -> fmt::Result {
+        if !self.cleared && self.clear_screen {
+            self.clear();
+        }
         for c in s.chars() {
             self.write_char(c);
         }
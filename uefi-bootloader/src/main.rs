@@ -9,31 +9,51 @@
 
 mod arch;
 mod boot_info;
+mod chainload;
+mod config;
 mod context;
+mod ext2;
+mod firmware;
 mod kernel;
 mod logger;
+mod manifest;
 mod mappings;
 mod memory;
 mod modules;
+mod multiboot2;
+mod selftest;
+mod source;
+mod uga;
 mod util;
 
 use crate::{
     arch::jump_to_kernel,
-    memory::{Frame, VirtualAddress},
+    config::{Config, Handoff, Resolution},
+    memory::{Frame, PhysicalAddress, VirtualAddress, DEVICE_TREE_MEMORY},
 };
-use core::{fmt::Write, ptr::NonNull};
-use log::{error, info};
+use core::{
+    fmt::{self, Write},
+    ptr::NonNull,
+};
+use log::{error, info, warn};
 use uefi::{
-    prelude::entry,
-    proto::console::gop::{self, GraphicsOutput},
+    guid,
+    prelude::{cstr16, entry},
+    proto::{
+        console::gop::{self, GraphicsOutput},
+        media::file::{File, FileAttribute, FileHandle, FileMode, RegularFile},
+    },
     table::{
+        boot::BootServices,
         cfg::{ACPI2_GUID, ACPI_GUID},
+        runtime::VariableVendor,
         Boot, SystemTable,
     },
-    Handle, Status,
+    Guid, Handle, Status,
 };
 use uefi_bootloader_api::{BootInformation, FrameBuffer, FrameBufferInfo, PixelFormat};
 
+pub(crate) use boot_info::SystemInfo;
 pub(crate) use context::{BootContext, RuntimeContext};
 
 static mut SYSTEM_TABLE: Option<NonNull<SystemTable<Boot>>> = None;
@@ -44,29 +64,65 @@ fn main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     // SAFETY: We are the sole thread.
     unsafe { SYSTEM_TABLE = Some(system_table_pointer) };
 
-    system_table
-        .stdout()
-        .clear()
-        .expect("failed to clear stdout");
+    // Until the framebuffer is set up, log records are written to the console.
+    init_logger();
 
-    let mut frame_buffer = get_frame_buffer(&system_table);
+    // The configuration is needed to set up the framebuffer.
+    // SAFETY: The original system table isn't used after exiting boot services.
+    let mut context = BootContext::new(handle, unsafe { system_table.unsafe_clone() });
+    if context.config.quiet {
+        log::set_max_level(log::LevelFilter::Error);
+    }
+    if context.config.boot_log {
+        init_log_file(&context);
+    }
+
+    let mut frame_buffer = get_frame_buffer(handle, &system_table, context.config.resolution);
     if let Some(frame_buffer) = frame_buffer {
-        init_logger(&frame_buffer);
+        init_frame_buffer_logger(&system_table, &frame_buffer, &context.config);
         info!("using framebuffer at {:#x}", frame_buffer.physical);
     }
 
     // SAFETY: We are the sole thread.
     unsafe { SYSTEM_TABLE = None };
 
-    let rsdp_address = get_rsdp_address(&system_table);
+    let tsc_frequency = arch::tsc_frequency(&system_table);
+    if let Some(tsc_frequency) = tsc_frequency {
+        info!("measured tsc frequency: {tsc_frequency} Hz");
+    }
+    let bootloader_image = context.bootloader_image();
+    match bootloader_image {
+        Some(image) => info!(
+            "bootloader image at {:#x}..{:#x}",
+            image.start,
+            image.start + image.len
+        ),
+        None => warn!("failed to get bootloader image"),
+    }
+    let system_info = SystemInfo::new(
+        &system_table,
+        get_rsdp_address(&system_table),
+        get_device_tree_address(&context),
+        get_secure_boot(&system_table),
+        tsc_frequency,
+        bootloader_image,
+    );
+    info!(
+        "firmware: {} (revision {:#x})",
+        system_info.firmware_vendor(),
+        system_info.firmware_revision
+    );
 
-    let mut context = BootContext::new(handle, system_table);
     let (entry_point, elf_sections) = context.load_kernel();
     info!("loaded kernel");
     // This may take a sec.
     info!("loading modules...");
-    let modules = context.load_modules();
-    info!("loaded modules");
+    let (modules, modules_address) = context.load_modules();
+    info!("loaded {} modules", modules.len());
+
+    if context.config.selftest {
+        context.self_test_allocation();
+    }
 
     let mut context = context.exit_boot_services();
 
@@ -74,15 +130,47 @@ fn main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     info!("created memory mappings");
 
     let page_table_frame = context.page_table();
-    info!(
-        "page table located at: {:#x}",
-        page_table_frame.start_address()
-    );
+    info!("page table located at: {page_table_frame}");
+
+    let boot_info = match context.config.handoff {
+        Handoff::Native => {
+            let boot_info =
+                context.create_boot_info(frame_buffer, &system_info, modules, elf_sections);
+            info!("created boot info: {boot_info:x?}");
+            BootInfo::Native(boot_info)
+        }
+        Handoff::Multiboot2Info => {
+            let address = context.create_multiboot2_info(
+                frame_buffer,
+                system_info.rsdp_address,
+                modules,
+                modules_address,
+            );
+            info!("created multiboot2 information at: {address:#x}");
+            BootInfo::Multiboot2(address)
+        }
+    };
 
-    let boot_info = context.create_boot_info(frame_buffer, rsdp_address, modules, elf_sections);
-    info!("created boot info: {boot_info:x?}");
+    if context.config.zero_frames {
+        let frames = context.frame_allocator.zeroed_frames();
+        let ticks = context.frame_allocator.zeroing_ticks();
+        match ticks.zip(tsc_frequency) {
+            Some((ticks, frequency)) => info!(
+                "zeroed {frames} frames in {} us",
+                u128::from(ticks) * 1_000_000 / u128::from(frequency)
+            ),
+            None => info!("zeroed {frames} frames"),
+        }
+    }
 
-    info!("about to jump to kernel: {:x?}", entry_point.value());
+    if context.config.selftest {
+        context.self_test();
+        context.self_test_alloc_policy();
+        arch::halt();
+    }
+
+    info!("{}", EnabledFeatures(&arch::enabled_features()));
+    info!("about to jump to kernel: {entry_point}");
     // SAFETY: Everything is correctly mapped.
     unsafe {
         jump_to_kernel(KernelContext {
@@ -100,34 +188,101 @@ struct KernelContext {
     page_table_frame: Frame,
     stack_top: VirtualAddress,
     entry_point: VirtualAddress,
-    boot_info: &'static BootInformation,
+    boot_info: BootInfo,
 }
 
-fn get_frame_buffer(system_table: &SystemTable<Boot>) -> Option<FrameBuffer> {
-    let handle = system_table
-        .boot_services()
-        .get_handle_for_protocol::<GraphicsOutput>()
-        .ok()?;
-    let mut gop = system_table
-        .boot_services()
-        .open_protocol_exclusive::<GraphicsOutput>(handle)
-        .ok()?;
+/// The information passed to the kernel.
+#[derive(Debug)]
+pub(crate) enum BootInfo {
+    Native(&'static BootInformation),
+    /// The physical address of a Multiboot2 information structure.
+    Multiboot2(PhysicalAddress),
+}
+
+/// Formats the enabled CPU features as a space-separated list.
+struct EnabledFeatures<'a>(&'a [(&'static str, bool)]);
+
+impl fmt::Display for EnabledFeatures<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("enabled:")?;
+        let mut enabled = self.0.iter().filter(|(_, enabled)| *enabled).peekable();
+        if enabled.peek().is_none() {
+            return f.write_str(" none");
+        }
+        for (name, _) in enabled {
+            write!(f, " {name}")?;
+        }
+        Ok(())
+    }
+}
+
+fn get_frame_buffer(
+    image_handle: Handle,
+    system_table: &SystemTable<Boot>,
+    resolution: Resolution,
+) -> Option<FrameBuffer> {
+    let handle = match firmware::find_handle::<GraphicsOutput>(system_table) {
+        Some(handle) => handle,
+        None => {
+            if resolution != Resolution::Current {
+                warn!("the resolution can't be set using UGA");
+            }
+            return uga::get_frame_buffer(image_handle, system_table);
+        }
+    };
+    let mut gop = firmware::open_protocol::<GraphicsOutput>(system_table, handle, image_handle)?;
+
+    let max_resolution = match resolution {
+        Resolution::Current => None,
+        Resolution::Max => Some((usize::MAX, usize::MAX)),
+        Resolution::Preferred(width, height) => Some((width, height)),
+    };
+    if let Some(max_resolution) = max_resolution {
+        if !set_best_mode(system_table.boot_services(), &mut gop, max_resolution) {
+            warn!("failed to set a graphics mode, using the current mode");
+        }
+    }
 
     let mode_info = gop.current_mode_info();
+    let pixel_format = match mode_info.pixel_format() {
+        gop::PixelFormat::Rgb => PixelFormat::Rgb,
+        gop::PixelFormat::Bgr => PixelFormat::Bgr,
+        gop::PixelFormat::Bitmask | gop::PixelFormat::BltOnly => {
+            warn!("Bitmask and BltOnly framebuffers are not supported");
+            return None;
+        }
+    };
     let mut frame_buffer = gop.frame_buffer();
+    let (width, height) = mode_info.resolution();
+    let bytes_per_pixel = 4;
+    let stride = mode_info.stride();
+    let reported_size = frame_buffer.size();
+
+    // The mode's pixels per scan line is more reliable than the size, which some
+    // firmwares report as `width * height * bytes_per_pixel`.
+    let pitch_bytes = stride * bytes_per_pixel;
+    if height != 0 && reported_size / height != pitch_bytes {
+        warn!(
+            "framebuffer size implies a pitch of {} bytes, using {pitch_bytes} bytes",
+            reported_size / height
+        );
+    }
+
+    // The larger of the two sizes is used so that the mapping covers every row the
+    // logger writes to.
+    let size = reported_size.max(pitch_bytes * height);
+    if size != reported_size {
+        warn!("framebuffer size reported as {reported_size:#x}, using {size:#x} to cover stride");
+    }
+
     let info = FrameBufferInfo {
-        size: frame_buffer.size(),
-        width: mode_info.resolution().0,
-        height: mode_info.resolution().1,
-        pixel_format: match mode_info.pixel_format() {
-            gop::PixelFormat::Rgb => PixelFormat::Rgb,
-            gop::PixelFormat::Bgr => PixelFormat::Bgr,
-            gop::PixelFormat::Bitmask | gop::PixelFormat::BltOnly => {
-                panic!("Bitmask and BltOnly framebuffers are not supported")
-            }
-        },
-        bytes_per_pixel: 4,
-        stride: mode_info.stride(),
+        size,
+        width,
+        height,
+        pixel_format,
+        bytes_per_pixel,
+        stride,
+        pitch_bytes,
     };
 
     Some(FrameBuffer {
@@ -137,24 +292,304 @@ fn get_frame_buffer(system_table: &SystemTable<Boot>) -> Option<FrameBuffer> {
     })
 }
 
-fn init_logger(frame_buffer: &FrameBuffer) {
+/// The number of times a graphics operation is retried if the GPU isn't
+/// ready.
+const GOP_RETRIES: usize = 3;
+
+/// How long to wait before retrying a graphics operation, in microseconds.
+const GOP_RETRY_DELAY: usize = 100_000;
+
+/// Sets the graphics mode with the largest resolution no larger than
+/// `max_resolution`.
+///
+/// Firmwares may fail to set modes they advertise, so progressively smaller
+/// modes are tried until one is set. Returns `false` if no mode could be set.
+///
+/// Some GPUs aren't ready shortly after a cold boot, in which case querying
+/// modes or setting a mode fails with a device error. Both are retried a few
+/// times before giving up.
+fn set_best_mode(
+    boot_services: &BootServices,
+    gop: &mut GraphicsOutput,
+    max_resolution: (usize, usize),
+) -> bool {
+    let key = |mode: &gop::Mode| {
+        let (width, height) = mode.info().resolution();
+        (width * height, width, height)
+    };
+
+    // Modes that fail to be queried are skipped, so a GPU that isn't ready has no
+    // modes.
+    for attempt in 1..=GOP_RETRIES {
+        if gop.modes().next().is_some() {
+            break;
+        }
+        warn!("no graphics modes available, retrying ({attempt}/{GOP_RETRIES})");
+        boot_services.stall(GOP_RETRY_DELAY);
+    }
+
+    let mut previous = None;
+    loop {
+        let candidate = gop
+            .modes()
+            .filter(|mode| {
+                let (width, height) = mode.info().resolution();
+                matches!(
+                    mode.info().pixel_format(),
+                    gop::PixelFormat::Rgb | gop::PixelFormat::Bgr
+                ) && width <= max_resolution.0
+                    && height <= max_resolution.1
+                    && previous.map_or(true, |previous| key(mode) < previous)
+            })
+            .max_by_key(key);
+
+        let mode = match candidate {
+            Some(mode) => mode,
+            None => return false,
+        };
+        previous = Some(key(&mode));
+
+        let (width, height) = mode.info().resolution();
+        let mut attempt = 0;
+        let result = loop {
+            match gop.set_mode(&mode) {
+                Err(error) if error.status() == Status::DEVICE_ERROR && attempt < GOP_RETRIES => {
+                    attempt += 1;
+                    warn!(
+                        "failed to set graphics mode to {width}x{height}: {:?}, retrying \
+                         ({attempt}/{GOP_RETRIES})",
+                        error.status()
+                    );
+                    boot_services.stall(GOP_RETRY_DELAY);
+                }
+                result => break result,
+            }
+        };
+        match result {
+            Ok(()) => {
+                info!("set graphics mode to {width}x{height}");
+                return true;
+            }
+            Err(error) => warn!(
+                "failed to set graphics mode to {width}x{height}: {:?}",
+                error.status()
+            ),
+        }
+    }
+}
+
+fn init_logger() {
+    let logger = logger::LOGGER.call_once(logger::LockedLogger::new);
+    log::set_logger(logger).expect("logger already set");
+    log::set_max_level(log::LevelFilter::Trace);
+}
+
+/// How long the framebuffer test pattern is displayed for, in microseconds.
+const TEST_PATTERN_DURATION: usize = 3_000_000;
+
+fn init_frame_buffer_logger(
+    system_table: &SystemTable<Boot>,
+    frame_buffer: &FrameBuffer,
+    config: &Config,
+) {
     // SAFETY: The hardware initialised the frame buffer.
     let slice = unsafe {
         core::slice::from_raw_parts_mut(frame_buffer.physical as *mut _, frame_buffer.info.size)
     };
-    let logger =
-        logger::LOGGER.call_once(move || logger::LockedLogger::new(slice, frame_buffer.info));
-    log::set_logger(logger).expect("logger already set");
-    log::set_max_level(log::LevelFilter::Trace);
+    let mut frame_buffer_logger =
+        logger::Logger::new(slice, frame_buffer.info, config.clear_screen);
+    if config.test_pattern {
+        frame_buffer_logger.draw_test_pattern();
+        system_table.boot_services().stall(TEST_PATTERN_DURATION);
+        frame_buffer_logger.clear();
+    }
+    if let Some(logger) = logger::LOGGER.get() {
+        logger.set_frame_buffer(frame_buffer_logger);
+    }
+}
+
+fn init_log_file(context: &BootContext) {
+    let file = context
+        .open_file_system_root()
+        .and_then(|mut root| {
+            root.open(
+                cstr16!("boot.log"),
+                FileMode::CreateReadWrite,
+                FileAttribute::empty(),
+            )
+            .ok()
+        })
+        .and_then(FileHandle::into_regular_file);
+
+    match file {
+        Some(mut file) => {
+            // Append to the existing log.
+            if file.set_position(RegularFile::END_OF_FILE).is_ok() {
+                if let Some(logger) = logger::LOGGER.get() {
+                    logger.set_file(file);
+                }
+            }
+        }
+        None => warn!("failed to open boot log file"),
+    }
 }
 
 fn get_rsdp_address(system_table: &SystemTable<Boot>) -> Option<usize> {
-    let mut config_entries = system_table.config_table().iter();
-    // look for an ACPI2 RSDP first
-    let acpi2_rsdp = config_entries.find(|entry| matches!(entry.guid, ACPI2_GUID));
-    // if no ACPI2 RSDP is found, look for a ACPI1 RSDP
-    let rsdp = acpi2_rsdp.or_else(|| config_entries.find(|entry| matches!(entry.guid, ACPI_GUID)));
-    rsdp.map(|entry| entry.address as usize)
+    let config_entries = system_table.config_table();
+    let candidates = || {
+        // look for ACPI2 RSDPs first
+        let acpi2_rsdps = config_entries
+            .iter()
+            .filter(|entry| matches!(entry.guid, ACPI2_GUID));
+        // then look for ACPI1 RSDPs
+        let acpi1_rsdps = config_entries
+            .iter()
+            .filter(|entry| matches!(entry.guid, ACPI_GUID));
+        acpi2_rsdps
+            .chain(acpi1_rsdps)
+            .map(|entry| entry.address as usize)
+    };
+
+    // SAFETY: The firmware provided the addresses, and physical memory is
+    // identity-mapped.
+    let valid_rsdp = candidates().find(|address| unsafe { is_rsdp_checksum_valid(*address) });
+    if valid_rsdp.is_some() {
+        return valid_rsdp;
+    }
+
+    // Some firmware provides RSDPs with invalid checksums, in which case the
+    // kernel can decide whether to trust it.
+    let rsdp = candidates().next()?;
+    warn!("RSDP at {rsdp:#x} has an invalid checksum");
+    Some(rsdp)
+}
+
+/// The GUID of the device tree configuration table (`EFI_DTB_TABLE_GUID`).
+const DEVICE_TREE_GUID: Guid = guid!("b1b621d5-f19c-41a5-830b-d9152c69aae0");
+
+/// The magic value at the start of a flattened device tree.
+const DEVICE_TREE_MAGIC: u32 = 0xd00d_feed;
+
+/// The size of a flattened device tree header.
+const DEVICE_TREE_HEADER_LEN: usize = 40;
+
+/// The maximum size of a device tree that is copied, which is the same limit
+/// Linux imposes.
+const MAX_DEVICE_TREE_LEN: usize = 2 * 1024 * 1024;
+
+/// Returns the address of a copy of the firmware's device tree.
+fn get_device_tree_address(context: &BootContext) -> Option<usize> {
+    let address = context
+        .system_table()
+        .config_table()
+        .iter()
+        .find(|entry| entry.guid == DEVICE_TREE_GUID)?
+        .address as usize;
+
+    // The header fields are big-endian.
+    // SAFETY: The firmware provided the address, and physical memory is
+    // identity-mapped.
+    let (magic, len) = unsafe {
+        (
+            u32::from_be(core::ptr::read_unaligned(address as *const u32)),
+            u32::from_be(core::ptr::read_unaligned((address + 4) as *const u32)),
+        )
+    };
+    if magic != DEVICE_TREE_MAGIC {
+        warn!("device tree at {address:#x} has an invalid magic value: {magic:#x}");
+        return None;
+    }
+
+    let len = len as usize;
+    if !(DEVICE_TREE_HEADER_LEN..=MAX_DEVICE_TREE_LEN).contains(&len) {
+        warn!("device tree at {address:#x} has an invalid size: {len:#x}");
+        return None;
+    }
+
+    // The firmware's copy may be in boot services memory, which the kernel is free
+    // to reuse.
+    let copy = context.allocate_byte_slice(len, DEVICE_TREE_MEMORY);
+    // SAFETY: The header specifies the device tree's length.
+    copy.copy_from_slice(unsafe { core::slice::from_raw_parts(address as *const u8, len) });
+    info!(
+        "copied device tree from {address:#x} to {:#x}",
+        copy.as_ptr() as usize
+    );
+    Some(copy.as_ptr() as usize)
+}
+
+/// The length of the ACPI 1.0 fields of the RSDP, which are covered by the
+/// first checksum.
+const RSDP_V1_LEN: usize = 20;
+
+/// The minimum length of an ACPI 2.0+ RSDP.
+const RSDP_V2_MIN_LEN: usize = 36;
+
+/// The maximum length of an RSDP that is trusted.
+///
+/// The length is provided by the firmware, so a corrupt value could
+/// otherwise cause reads far past the table.
+const RSDP_MAX_LEN: usize = 4096;
+
+/// Returns the bytes of the RSDP at the given address, or `None` if its
+/// length is invalid.
+///
+/// # Safety
+///
+/// `address` must point to an RSDP in identity-mapped memory.
+pub(crate) unsafe fn rsdp_bytes(address: usize) -> Option<&'static [u8]> {
+    // SAFETY: Guaranteed by caller.
+    let revision = unsafe { *((address + 15) as *const u8) };
+    let len = if revision < 2 {
+        RSDP_V1_LEN
+    } else {
+        // The length of the entire table is stored after the ACPI 1.0 fields.
+        // SAFETY: Guaranteed by caller.
+        let len =
+            unsafe { core::ptr::read_unaligned((address + RSDP_V1_LEN) as *const u32) as usize };
+        if !(RSDP_V2_MIN_LEN..=RSDP_MAX_LEN).contains(&len) {
+            return None;
+        }
+        len
+    };
+    // SAFETY: Guaranteed by caller, and the length was checked to be reasonable.
+    Some(unsafe { core::slice::from_raw_parts(address as *const u8, len) })
+}
+
+/// Returns whether the RSDP at the given address has a valid checksum.
+///
+/// # Safety
+///
+/// `address` must point to an RSDP in identity-mapped memory.
+unsafe fn is_rsdp_checksum_valid(address: usize) -> bool {
+    fn sums_to_zero(bytes: &[u8]) -> bool {
+        bytes.iter().fold(0_u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+    }
+
+    // SAFETY: Guaranteed by caller.
+    let v1_bytes = unsafe { core::slice::from_raw_parts(address as *const u8, RSDP_V1_LEN) };
+    if !sums_to_zero(v1_bytes) {
+        return false;
+    }
+
+    // The extended checksum covers the entire table. For ACPI 1.0 RSDPs, the
+    // entire table is the ACPI 1.0 fields.
+    // SAFETY: Guaranteed by caller.
+    unsafe { rsdp_bytes(address) }.map_or(false, sums_to_zero)
+}
+
+fn get_secure_boot(system_table: &SystemTable<Boot>) -> Option<bool> {
+    let mut buffer = [0; 1];
+    // The variable doesn't exist if the firmware doesn't support secure boot.
+    let (value, _) = system_table
+        .runtime_services()
+        .get_variable(
+            cstr16!("SecureBoot"),
+            &VariableVendor::GLOBAL_VARIABLE,
+            &mut buffer,
+        )
+        .ok()?;
+    value.first().map(|value| *value == 1)
 }
 
 #[panic_handler]
@@ -171,6 +606,8 @@ fn panic(info: &core::panic::PanicInfo<'_>) -> ! {
         unsafe { logger.force_unlock() };
     }
     error!("{info}");
+    // Persist the panic message if we are still logging to a file.
+    log::logger().flush();
 
     arch::halt();
 }
@@ -0,0 +1,114 @@
+//! Support for loading a kernel made of raw binary blobs.
+//!
+//! Some toolchains don't emit ELF files, so the kernel can instead be loaded
+//! from raw binaries described by a manifest. Each line of the manifest has
+//! the form `key = value`, and empty lines and lines starting with `#` are
+//! ignored. The `entry` key specifies the virtual address of the entry point.
+//! Every other key is the path of a blob on the boot volume, and its value has
+//! the form `<vaddr>, <flags>[, <size>]`, where `flags` is a combination of
+//! `r`, `w`, and `x`, and `size` is the size of the blob in memory. If `size`
+//! is larger than the file, the remaining bytes are zeroed.
+
+use crate::{
+    config::parse_usize, kernel::validate_load_segment, memory::VirtualAddress, BootContext,
+};
+use goblin::elf64::program_header::{ProgramHeader, PF_R, PF_W, PF_X, PT_LOAD};
+use log::info;
+use uefi::table::boot::MemoryType;
+use uefi_bootloader_api::ElfSection;
+
+impl BootContext {
+    /// Loads the kernel described by the manifest at `path`, returning the
+    /// entry point.
+    ///
+    /// Raw binaries don't have sections, so no ELF sections are returned.
+    pub(crate) fn load_kernel_from_manifest(
+        &mut self,
+        path: &str,
+    ) -> (VirtualAddress, &'static mut [ElfSection]) {
+        let manifest = self
+            .read_file(path, MemoryType::LOADER_DATA)
+            .unwrap_or_else(|| panic!("failed to read kernel manifest {path}"));
+        let manifest = core::str::from_utf8(manifest).expect("kernel manifest is not valid UTF-8");
+
+        let mut entry_point = None;
+
+        for (i, line) in manifest.lines().enumerate() {
+            let line_number = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .map(|(key, value)| (key.trim(), value.trim()))
+                .unwrap_or_else(|| panic!("malformed line {line_number} in kernel manifest"));
+
+            if key == "entry" {
+                entry_point = Some(parse_address(value, line_number));
+            } else {
+                self.load_blob(key, value, i as u64, line_number);
+            }
+        }
+
+        let entry_point = entry_point.expect("kernel manifest doesn't specify an entry point");
+        (entry_point, &mut [])
+    }
+
+    fn load_blob(&mut self, path: &str, value: &str, index: u64, line_number: usize) {
+        let mut fields = value.split(',').map(str::trim);
+        let virtual_address = fields
+            .next()
+            .map(|address| parse_address(address, line_number).value())
+            .unwrap_or_else(|| panic!("missing address on line {line_number} in kernel manifest"));
+        let flags = fields
+            .next()
+            .and_then(parse_flags)
+            .unwrap_or_else(|| panic!("invalid flags on line {line_number} in kernel manifest"));
+
+        let bytes = self
+            .read_file(path, MemoryType::LOADER_DATA)
+            .unwrap_or_else(|| panic!("failed to read kernel blob {path}"));
+        let size = match fields.next() {
+            Some(size) => parse_usize(size)
+                .filter(|size| *size >= bytes.len())
+                .unwrap_or_else(|| panic!("invalid size on line {line_number} in kernel manifest")),
+            None => bytes.len(),
+        };
+        assert!(size > 0, "kernel blob {path} is empty");
+
+        // The blob is loaded like an ELF load segment, so that it's mapped in the
+        // same way.
+        let segment = ProgramHeader {
+            p_type: PT_LOAD,
+            p_flags: flags,
+            p_offset: 0,
+            p_vaddr: virtual_address as u64,
+            p_paddr: 0,
+            p_filesz: bytes.len() as u64,
+            p_memsz: size as u64,
+            p_align: 0,
+        };
+        validate_load_segment(index, &segment);
+
+        info!("loading blob {path}: {segment:?}");
+        let slice = self.map_segment(&segment);
+        slice[..bytes.len()].copy_from_slice(bytes);
+    }
+}
+
+fn parse_address(value: &str, line_number: usize) -> VirtualAddress {
+    parse_usize(value)
+        .and_then(VirtualAddress::new)
+        .unwrap_or_else(|| panic!("invalid address on line {line_number} in kernel manifest"))
+}
+
+fn parse_flags(value: &str) -> Option<u32> {
+    value.chars().try_fold(0, |flags, c| match c {
+        'r' => Some(flags | PF_R),
+        'w' => Some(flags | PF_W),
+        'x' => Some(flags | PF_X),
+        _ => None,
+    })
+}
@@ -1,6 +1,11 @@
 use crate::{
     jump_to_kernel,
-    memory::{Frame, FrameAllocator, Page, PhysicalAddress, PteFlags, VirtualAddress},
+    memory::{
+        Frame, FrameAllocator, Page, PageRange, PhysicalAddress, PteFlags, VirtualAddress,
+        PAGE_SIZE,
+    },
+    selftest::Target,
+    util::calculate_pages,
     FrameBuffer, RuntimeContext,
 };
 
@@ -11,77 +16,136 @@ impl RuntimeContext {
     ) -> VirtualAddress {
         // TODO: Enable nxe and write protect bits on x86_64.
 
-        // TODO: Depend on kernel_config?
-        const STACK_SIZE: usize = 18 * 4096;
+        let stack_size = calculate_pages(self.config.stack_size) * PAGE_SIZE;
+        let stack_align = self.config.stack_align.max(PAGE_SIZE);
 
-        let stack_start_address = self.page_allocator.get_free_address(STACK_SIZE);
+        let guard_pages = self.config.stack_guard_pages;
+        let guard_size = guard_pages
+            .checked_mul(PAGE_SIZE)
+            .expect("stack guard size overflowed");
 
-        let stack_start = Page::containing_address(stack_start_address);
-        let stack_end = {
-            let end_address = stack_start_address + STACK_SIZE;
-            Page::containing_address(end_address - 1)
-        };
+        // Extra space is reserved so that the top of the stack can be aligned.
+        let stack_region_size = guard_size
+            .checked_add(stack_size)
+            .and_then(|size| size.checked_add(stack_align - PAGE_SIZE))
+            .expect("stack region size overflowed");
+        let stack_region_address = self.page_allocator.get_free_address(stack_region_size);
+        let stack_top = stack_region_address
+            .checked_add(stack_region_size + PAGE_SIZE - 1)
+            .map(|address| VirtualAddress::new_canonical(address.value() & !(stack_align - 1)))
+            .unwrap_or_else(|| {
+                panic!("stack at {stack_region_address} extends past the address space")
+            });
 
-        // The +1 means the guard page isn't mapped to a frame.
-        for page in (stack_start + 1)..=stack_end {
+        let stack_start = Page::containing_address(stack_top - stack_size - guard_size);
+        let stack_end = Page::containing_address(stack_top - 1);
+
+        let stack_flags = PteFlags::new()
+            .present(true)
+            .writable(true)
+            .no_execute(true);
+
+        // The guard pages below the stack aren't mapped to frames, so overflowing the
+        // stack by less than `guard_size` bytes causes a page fault.
+        for page in (stack_start + guard_pages)..=stack_end {
             let frame = self
                 .frame_allocator
                 .allocate_frame()
                 .expect("failed to allocate stack frame");
-            self.mapper.map(
-                page,
-                frame,
-                PteFlags::new()
-                    .present(true)
-                    .writable(true)
-                    .no_execute(true),
-                &mut self.frame_allocator,
+            self.mapper
+                .map(page, frame, stack_flags, &mut self.frame_allocator);
+        }
+
+        if guard_pages > 0 {
+            self.mappings.record(
+                "stack guard",
+                PageRange::new(stack_start, stack_start + (guard_pages - 1)),
+                Target::Unmapped,
+                stack_flags,
             );
         }
+        self.mappings.record(
+            "stack",
+            PageRange::new(stack_start + guard_pages, stack_end),
+            Target::Any,
+            stack_flags,
+        );
 
-        if let Some(frame_buffer) = frame_buffer {
+        // If the framebuffer isn't mapped, its virtual address is left as zero.
+        if let Some(frame_buffer) = frame_buffer.filter(|_| self.config.map_frame_buffer) {
             let frame_buffer_start_address =
                 self.page_allocator.get_free_address(frame_buffer.info.size);
             let frame_buffer_virtual_start = Page::containing_address(frame_buffer_start_address);
-            let frame_buffer_virtual_end = {
-                let end_address =
-                    frame_buffer_virtual_start.start_address() + frame_buffer.info.size;
-                Page::containing_address(end_address - 1)
-            };
-
-            let frame_buffer_physical_start =
-                Frame::containing_address(PhysicalAddress::new_canonical(frame_buffer.physical));
-            let frame_buffer_physical_end = {
-                let end_address =
-                    frame_buffer_physical_start.start_address() + frame_buffer.info.size;
-                Frame::containing_address(end_address - 1)
-            };
+            let frame_buffer_virtual_end = frame_buffer_virtual_start
+                .start_address()
+                .checked_add(frame_buffer.info.size - 1)
+                .map(Page::containing_address)
+                .unwrap_or_else(|| {
+                    panic!(
+                        "frame buffer at {frame_buffer_start_address} extends past the address \
+                         space"
+                    )
+                });
+
+            let frame_buffer_physical_start = PhysicalAddress::new(frame_buffer.physical)
+                .map(Frame::containing_address)
+                .unwrap_or_else(|| {
+                    panic!(
+                        "frame buffer physical address {:#x} is non-canonical",
+                        frame_buffer.physical
+                    )
+                });
+            let frame_buffer_physical_end = frame_buffer_physical_start
+                .start_address()
+                .checked_add(frame_buffer.info.size - 1)
+                .map(Frame::containing_address)
+                .unwrap_or_else(|| {
+                    panic!(
+                        "frame buffer at {:#x} extends past the physical address space",
+                        frame_buffer.physical
+                    )
+                });
+
+            let frame_buffer_flags = PteFlags::new()
+                .present(true)
+                .writable(true)
+                .no_execute(true);
 
             for (page, frame) in (frame_buffer_virtual_start..=frame_buffer_virtual_end)
-                .zip(frame_buffer_physical_start..frame_buffer_physical_end)
+                .zip(frame_buffer_physical_start..=frame_buffer_physical_end)
             {
-                self.mapper.map(
-                    page,
-                    frame,
-                    PteFlags::new()
-                        .present(true)
-                        .writable(true)
-                        .no_execute(true),
-                    &mut self.frame_allocator,
-                );
+                self.mapper
+                    .map(page, frame, frame_buffer_flags, &mut self.frame_allocator);
             }
 
+            self.mappings.record(
+                "frame buffer",
+                PageRange::new(frame_buffer_virtual_start, frame_buffer_virtual_end),
+                Target::Frames(frame_buffer_physical_start),
+                frame_buffer_flags,
+            );
+
             frame_buffer.virt = frame_buffer_start_address.value();
         }
 
         // Identity-map the context switch function so that when it switches to the new
         // page table, it continues executing.
-        self.mapper.map(
-            Page::containing_address(VirtualAddress::new_canonical(jump_to_kernel as usize)),
-            Frame::containing_address(PhysicalAddress::new_canonical(jump_to_kernel as usize)),
-            PteFlags::new().present(true),
+        let jump_to_kernel_frame =
+            Frame::containing_address(PhysicalAddress::new_canonical(jump_to_kernel as usize));
+        let jump_to_kernel_page =
+            Page::containing_address(VirtualAddress::new_canonical(jump_to_kernel as usize));
+        let jump_to_kernel_flags = PteFlags::new().present(true);
+        self.mapper.identity_map(
+            jump_to_kernel_frame,
+            jump_to_kernel_flags,
             &mut self.frame_allocator,
         );
+        self.mappings.record(
+            "context switch function",
+            PageRange::new(jump_to_kernel_page, jump_to_kernel_page),
+            Target::Frames(jump_to_kernel_frame),
+            jump_to_kernel_flags,
+        );
 
         crate::memory::set_up_arch_specific_mappings(self);
 
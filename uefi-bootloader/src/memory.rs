@@ -1,6 +1,6 @@
 // TODO: Depend on memory_structs
 
-use crate::arch::memory as imp;
+use crate::{arch::memory as imp, config::AllocPolicy};
 use core::{
     cmp::{max, min},
     fmt,
@@ -26,6 +26,9 @@ pub(crate) const PAGE_SIZE: usize = 4096;
 const MAX_PAGE_NUMBER: usize = usize::MAX / PAGE_SIZE;
 
 pub(crate) const KERNEL_MEMORY: MemoryType = MemoryType::custom(0xffff_ffff);
+/// The memory type of the copy of the firmware's device tree, which the
+/// kernel may use after the handoff.
+pub(crate) const DEVICE_TREE_MEMORY: MemoryType = MemoryType::custom(0x8000_0001);
 
 /// A macro for defining `VirtualAddress` and `PhysicalAddress` structs
 /// and implementing their common traits, which are generally identical.
@@ -77,15 +80,22 @@ macro_rules! implement_address {
                 pub(crate) const fn [<$chunk _offset>](&self) -> usize {
                     self.0 & (PAGE_SIZE - 1)
                 }
+
+                #[doc = "Adds `rhs` to this `" $TypeName "`, returning `None` if the result overflows or is not canonical.\n\n \
+                    Unlike the `Add` implementation, this doesn't saturate or canonicalize the result."]
+                pub(crate) fn checked_add(self, rhs: usize) -> Option<$TypeName> {
+                    self.0.checked_add(rhs).and_then($TypeName::new)
+                }
             }
             impl fmt::Debug for $TypeName {
                 fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
                     write!(f, concat!($prefix, "{:#X}"), self.0)
                 }
             }
+            #[doc = "Formats the address compactly as `" $prefix ":0x<address>`, in lowercase hexadecimal."]
             impl fmt::Display for $TypeName {
                 fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-                    write!(f, "{:?}", self)
+                    write!(f, concat!($prefix, ":{:#x}"), self.0)
                 }
             }
             impl fmt::Pointer for $TypeName {
@@ -173,12 +183,33 @@ macro_rules! implement_page_frame {
                         number: addr.value() / PAGE_SIZE,
                     }
                 }
+
+                #[doc = "Adds `rhs` to this `" $TypeName "`, returning `None` if the result exceeds the max " $desc " number.\n\n \
+                    Unlike the `Add` implementation, this doesn't saturate the result."]
+                pub(crate) fn checked_add(self, rhs: usize) -> Option<$TypeName> {
+                    self.number
+                        .checked_add(rhs)
+                        .filter(|number| *number <= MAX_PAGE_NUMBER)
+                        .map(|number| $TypeName { number })
+                }
             }
             impl fmt::Debug for $TypeName {
                 fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
                     write!(f, concat!(stringify!($TypeName), "(", $prefix, "{:#X})"), self.start_address())
                 }
             }
+            #[doc = "Formats the `" $TypeName "` compactly by its start address, e.g. `" $prefix ":0x1000`."]
+            impl fmt::Display for $TypeName {
+                fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                    fmt::Display::fmt(&self.start_address(), f)
+                }
+            }
+            #[doc = "Formats the start address of the `" $TypeName "` in lowercase hexadecimal."]
+            impl fmt::LowerHex for $TypeName {
+                fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                    fmt::LowerHex::fmt(&self.start_address(), f)
+                }
+            }
             impl Add<usize> for $TypeName {
                 type Output = $TypeName;
                 fn add(self, rhs: usize) -> $TypeName {
@@ -367,30 +398,143 @@ macro_rules! implement_page_frame_range {
 implement_page_frame_range!(PageRange, "virtual", virt, Page, VirtualAddress);
 implement_page_frame_range!(FrameRange, "physical", phys, Frame, PhysicalAddress);
 
+impl Mapper {
+    /// Maps `frame` to the page with the same number, so that the frame's
+    /// physical address is also a valid virtual address for it.
+    ///
+    /// This is needed for code and data that must stay accessible at the same
+    /// address across a page table switch (e.g. the context switch function).
+    ///
+    /// Panics if the frame's address isn't a canonical virtual address.
+    pub(crate) fn identity_map<T>(&mut self, frame: Frame, flags: PteFlags, frame_allocator: &mut T)
+    where
+        T: FrameAllocator,
+    {
+        let address = VirtualAddress::new(frame.start_address().value())
+            .unwrap_or_else(|| panic!("cannot identity map {frame:?}: non-canonical address"));
+        self.map(
+            Page::containing_address(address),
+            frame,
+            flags,
+            frame_allocator,
+        );
+    }
+}
+
+/// Returns the kind of memory described by the descriptor.
+///
+/// The bootloader's image and temporary allocations (e.g. file contents and
+/// the UEFI memory map) are loader memory, which the kernel can reclaim once
+/// it has read the boot info. Everything the kernel still uses after the
+/// handoff is either [`KERNEL_MEMORY`], [`DEVICE_TREE_MEMORY`], or allocated
+/// after exiting boot services, and so is reported as bootloader memory.
 fn descriptor_kind(memory_descriptor: &MemoryDescriptor) -> MemoryRegionKind {
     match memory_descriptor.ty {
         MemoryType::CONVENTIONAL
-        | MemoryType::LOADER_CODE
-        | MemoryType::LOADER_DATA
         | MemoryType::BOOT_SERVICES_CODE
         | MemoryType::BOOT_SERVICES_DATA => MemoryRegionKind::Usable,
+        MemoryType::LOADER_CODE | MemoryType::LOADER_DATA => {
+            MemoryRegionKind::BootloaderReclaimable
+        }
+        DEVICE_TREE_MEMORY => MemoryRegionKind::Bootloader,
+        MemoryType::ACPI_RECLAIM => MemoryRegionKind::AcpiReclaimable,
+        MemoryType::ACPI_NON_VOLATILE => MemoryRegionKind::AcpiNvs,
+        MemoryType::PERSISTENT_MEMORY => MemoryRegionKind::PersistentMemory,
         tag => MemoryRegionKind::UnknownUefi(tag.0),
     }
 }
 
+/// Sorts the regions by start address and merges adjacent regions of the same
+/// kind, returning the number of remaining regions.
+///
+/// The remaining regions are moved to the start of `regions`.
+fn compact_memory_map(regions: &mut [MemoryRegion]) -> usize {
+    regions.sort_unstable_by_key(|region| region.start);
+
+    // Firmwares usually return an already minimal memory map, in which case there's
+    // nothing to merge.
+    if regions.iter().all(|region| region.len != 0)
+        && !regions.windows(2).any(|pair| can_merge(&pair[0], &pair[1]))
+    {
+        return regions.len();
+    }
+
+    let mut len = 0;
+    for i in 0..regions.len() {
+        let region = regions[i];
+        if region.len == 0 {
+            continue;
+        }
+        match len.checked_sub(1).map(|last| &mut regions[last]) {
+            Some(last) if can_merge(last, &region) => {
+                last.len += region.len;
+            }
+            _ => {
+                regions[len] = region;
+                len += 1;
+            }
+        }
+    }
+    len
+}
+
+/// Returns whether `next` directly follows `region` and has the same kind.
+fn can_merge(region: &MemoryRegion, next: &MemoryRegion) -> bool {
+    region.kind == next.kind && region.start + region.len == next.start
+}
+
+/// Splits a usable region at `max_address`, reporting the memory above it as
+/// bootloader memory so that the kernel doesn't use it.
+fn split_at_max_address(
+    region: MemoryRegion,
+    max_address: Option<usize>,
+) -> [Option<MemoryRegion>; 2] {
+    let end = region.start + region.len;
+    let usable_end = match max_address {
+        Some(max_address) if region.kind == MemoryRegionKind::Usable && max_address < end => {
+            max_address.max(region.start)
+        }
+        _ => return [Some(region), None],
+    };
+
+    let usable = MemoryRegion {
+        start: region.start,
+        len: usable_end - region.start,
+        kind: MemoryRegionKind::Usable,
+    };
+    let excess = MemoryRegion {
+        start: usable_end,
+        len: end - usable_end,
+        kind: MemoryRegionKind::Bootloader,
+    };
+    [Some(usable).filter(|usable| usable.len > 0), Some(excess)]
+}
+
 pub(crate) trait FrameAllocator {
     fn allocate_frame(&mut self) -> Option<Frame>;
 }
 
+/// Returns the allocation type that allocates pages anywhere below
+/// `max_address`, if specified.
+pub(crate) fn allocate_type_below(max_address: Option<usize>) -> AllocateType {
+    match max_address {
+        // `MaxAddress` is an inclusive bound on the last byte of the allocation.
+        Some(max_address) => AllocateType::MaxAddress(max_address.saturating_sub(1) as u64),
+        None => AllocateType::AnyPages,
+    }
+}
+
 pub(crate) struct UefiFrameAllocator<'a> {
     pub(crate) system_table: &'a SystemTable<Boot>,
+    /// The address below which frames are allocated, if any.
+    pub(crate) max_address: Option<usize>,
 }
 
 impl FrameAllocator for UefiFrameAllocator<'_> {
     fn allocate_frame(&mut self) -> Option<Frame> {
         self.system_table
             .boot_services()
-            .allocate_pages(AllocateType::AnyPages, KERNEL_MEMORY, 1)
+            .allocate_pages(allocate_type_below(self.max_address), KERNEL_MEMORY, 1)
             .ok()
             .map(|address| {
                 Frame::containing_address(PhysicalAddress::new_canonical(address as usize))
@@ -398,130 +542,313 @@ impl FrameAllocator for UefiFrameAllocator<'_> {
     }
 }
 
+impl UefiFrameAllocator<'_> {
+    /// Allocates `count` contiguous frames that lie entirely below
+    /// `max_address`.
+    ///
+    /// This is useful for structures with physical address constraints, such
+    /// as AP trampolines or buffers used by 32-bit DMA devices. Returns `None`
+    /// if the firmware couldn't find enough contiguous frames below the limit.
+    pub(crate) fn allocate_frames_in_range(
+        &mut self,
+        count: usize,
+        max_address: PhysicalAddress,
+    ) -> Option<FrameRange> {
+        if count == 0 {
+            return None;
+        }
+        // `MaxAddress` is an inclusive bound on the last byte of the allocation.
+        let max_address = (max_address.value() as u64).checked_sub(1)?;
+
+        let start = self
+            .system_table
+            .boot_services()
+            .allocate_pages(AllocateType::MaxAddress(max_address), KERNEL_MEMORY, count)
+            .ok()?;
+        let start = Frame::containing_address(PhysicalAddress::new_canonical(start as usize));
+        Some(FrameRange::new(start, start + (count - 1)))
+    }
+}
+
 pub(crate) struct LegacyFrameAllocator {
     original: MemoryMapIter<'static>,
-    memory_map: MemoryMapIter<'static>,
+    /// The number of descriptors in the memory map.
+    descriptor_count: usize,
+    /// The number of descriptors, in allocation order, that have been
+    /// considered for allocation.
+    visited: usize,
     current_descriptor: Option<CurrentDescriptor>,
+    /// Whether frames are allocated from high or low physical memory.
+    policy: AllocPolicy,
+    /// Whether allocated frames are zeroed.
+    zero_frames: bool,
+    /// The address above which usable memory is ignored, if any.
+    max_address: Option<usize>,
+    /// The number of frames that have been zeroed.
+    zeroed_frames: usize,
+    /// The number of TSC ticks spent zeroing frames.
+    zeroing_ticks: u64,
 }
 
 struct CurrentDescriptor {
+    /// The index of the descriptor in allocation order.
+    index: usize,
     descriptor: &'static MemoryDescriptor,
+    /// The next frame to allocate.
+    ///
+    /// Frames are allocated in ascending order when allocating bottom-up, and
+    /// in descending order when allocating top-down.
     next_frame: Frame,
 }
 
 impl LegacyFrameAllocator {
-    pub(crate) fn new(memory_map: MemoryMapIter<'static>) -> Self {
+    /// Creates a new frame allocator.
+    ///
+    /// If `zero_frames` is set, every allocated frame is zeroed so that no
+    /// data from the firmware or a previous boot leaks into the kernel. If
+    /// `max_address` is set, frames are only allocated below it and usable
+    /// memory above it is reported as bootloader memory. `policy` determines
+    /// whether frames are allocated from high or low physical memory.
+    pub(crate) fn new(
+        memory_map: MemoryMapIter<'static>,
+        zero_frames: bool,
+        max_address: Option<usize>,
+        policy: AllocPolicy,
+    ) -> Self {
         Self {
-            original: memory_map.clone(),
-            memory_map,
+            descriptor_count: memory_map.clone().count(),
+            original: memory_map,
+            visited: 0,
             current_descriptor: None,
+            policy,
+            zero_frames,
+            max_address,
+            zeroed_frames: 0,
+            zeroing_ticks: 0,
         }
     }
 
+    /// Returns the number of frames that have been zeroed.
+    pub(crate) fn zeroed_frames(&self) -> usize {
+        self.zeroed_frames
+    }
+
+    /// Returns the number of TSC ticks spent zeroing frames, or `None` if the
+    /// architecture doesn't have a TSC.
+    pub(crate) fn zeroing_ticks(&self) -> Option<u64> {
+        crate::arch::timestamp().map(|_| self.zeroing_ticks)
+    }
+
     pub(crate) fn len(&self) -> usize {
-        // At most, one descriptor can be split.
-        self.original.clone().count() + 2
+        // At most, one descriptor can be split by the allocated frames, and one can be
+        // split by the maximum address.
+        self.original.clone().count() + 3
+    }
+
+    /// Allocates `count` physically contiguous frames.
+    ///
+    /// Frames allocated before a gap in usable memory are skipped, but remain
+    /// marked as bootloader memory in the memory map.
+    pub(crate) fn allocate_contiguous_frames(&mut self, count: usize) -> Option<FrameRange> {
+        let start = self.allocate_frame()?;
+        let mut frames = FrameRange::new(start, start);
+
+        while frames.size_in_frames() < count {
+            let frame = self.allocate_frame()?;
+            frames = if frame == *frames.end() + 1 {
+                FrameRange::new(*frames.start(), frame)
+            } else if frame + 1 == *frames.start() {
+                FrameRange::new(frame, *frames.end())
+            } else {
+                FrameRange::new(frame, frame)
+            };
+        }
+
+        Some(frames)
+    }
+
+    /// Returns the frame that will be allocated next from the current
+    /// descriptor, or `None` if the descriptor is exhausted.
+    pub(crate) fn peek_frame(&self) -> Option<Frame> {
+        let current_descriptor = self.current_descriptor.as_ref()?;
+        let descriptor = current_descriptor.descriptor;
+        let start_frame = Frame::containing_address(PhysicalAddress::new_canonical(
+            descriptor.phys_start as usize,
+        ));
+        let end_frame = Frame::containing_address(self.usable_end(descriptor) - 1);
+
+        let frame = current_descriptor.next_frame;
+        (start_frame..=end_frame).contains(&frame).then_some(frame)
     }
 
     fn allocate_frame_from_current(&mut self) -> Option<Frame> {
+        let frame = self.peek_frame()?;
         let current_descriptor = self.current_descriptor.as_mut()?;
+        match self.policy {
+            AllocPolicy::BottomUp => current_descriptor.next_frame += 1,
+            AllocPolicy::TopDown => current_descriptor.next_frame -= 1,
+        }
+        Some(frame)
+    }
 
-        let start_address =
-            PhysicalAddress::new_canonical(current_descriptor.descriptor.phys_start as usize);
-        let end_address =
-            start_address + (current_descriptor.descriptor.page_count as usize * PAGE_SIZE);
+    /// Returns the end of the usable part of the descriptor, which is below
+    /// the maximum address.
+    fn usable_end(&self, descriptor: &MemoryDescriptor) -> PhysicalAddress {
+        let start_address = PhysicalAddress::new_canonical(descriptor.phys_start as usize);
+        min(
+            start_address + (descriptor.page_count as usize * PAGE_SIZE),
+            PhysicalAddress::new_canonical(self.max_address.unwrap_or(usize::MAX)),
+        )
+    }
 
-        let end_frame = Frame::containing_address(end_address - 1);
+    /// Returns whether frames can be allocated from the descriptor.
+    fn is_allocatable(&self, descriptor: &MemoryDescriptor) -> bool {
+        // Allocating frames below 1MiB causes problems during AP boot.
+        descriptor_kind(descriptor) == MemoryRegionKind::Usable
+            && descriptor.phys_start >= 0x1_0000
+            && self.max_address.map_or(true, |max_address| {
+                (descriptor.phys_start as usize) < max_address
+            })
+    }
 
-        if current_descriptor.next_frame <= end_frame {
-            let frame = current_descriptor.next_frame;
-            current_descriptor.next_frame += 1;
-            Some(frame)
-        } else {
-            None
+    /// Converts between an index in memory map order and an index in
+    /// allocation order.
+    ///
+    /// Top-down allocation walks the memory map backwards, which relies on the
+    /// firmware sorting it by address, as all known firmwares do.
+    fn reorder(&self, index: usize) -> usize {
+        match self.policy {
+            AllocPolicy::BottomUp => index,
+            AllocPolicy::TopDown => self.descriptor_count - 1 - index,
         }
     }
 
-    pub(crate) fn construct_memory_map(
-        self,
-        memory_map: &mut [MaybeUninit<MemoryRegion>],
-    ) -> &mut [MemoryRegion] {
+    /// Constructs the memory map in `memory_map`.
+    ///
+    /// The memory map is only accurate if no frames are allocated afterwards.
+    pub(crate) fn construct_memory_map<'a>(
+        &self,
+        memory_map: &'a mut [MaybeUninit<MemoryRegion>],
+    ) -> &'a mut [MemoryRegion] {
         // We definetly allocated at least one frame, right?
         let current_descriptor = self
             .current_descriptor
+            .as_ref()
             .expect("failed to get current descriptor");
         let mut index = 0;
-        let mut iterated_through_used_descriptors = false;
-
-        for descriptor in self.original {
-            if iterated_through_used_descriptors
-                || descriptor.phys_start < 0x1_0000
-                || descriptor_kind(descriptor) != MemoryRegionKind::Usable
+        let max_address = self.max_address;
+        let mut push = |region| {
+            for region in split_at_max_address(region, max_address)
+                .into_iter()
+                .flatten()
             {
-                memory_map[index].write(MemoryRegion {
-                    start: descriptor.phys_start as usize,
-                    len: descriptor.page_count as usize * PAGE_SIZE,
-                    kind: descriptor_kind(descriptor),
-                });
+                // This only fails if the memory map wasn't sized using `len`, but writing
+                // out of bounds would corrupt whatever follows the memory map.
+                assert!(
+                    index < memory_map.len(),
+                    "more memory regions than reserved: {} reserved",
+                    memory_map.len()
+                );
+                memory_map[index].write(region);
                 index += 1;
-            } else if descriptor.phys_start == current_descriptor.descriptor.phys_start {
-                let used_len = current_descriptor.next_frame.start_address().value()
-                    - descriptor.phys_start as usize;
-                memory_map[index].write(MemoryRegion {
-                    start: descriptor.phys_start as usize,
-                    len: used_len,
-                    kind: MemoryRegionKind::Bootloader,
+            }
+        };
+        for (map_index, descriptor) in self.original.clone().enumerate() {
+            let kind = descriptor_kind(descriptor);
+            let start = descriptor.phys_start as usize;
+            let len = descriptor.page_count as usize * PAGE_SIZE;
+            let allocation_index = self.reorder(map_index);
+
+            if !self.is_allocatable(descriptor) || allocation_index > current_descriptor.index {
+                push(MemoryRegion { start, len, kind });
+            } else if allocation_index == current_descriptor.index {
+                // The allocated frames are at the start of the descriptor when allocating
+                // bottom-up, and at the end when allocating top-down.
+                let next_frame = current_descriptor.next_frame;
+                let (split, low_kind, high_kind) = match self.policy {
+                    AllocPolicy::BottomUp => (
+                        next_frame.start_address().value(),
+                        MemoryRegionKind::Bootloader,
+                        MemoryRegionKind::Usable,
+                    ),
+                    AllocPolicy::TopDown => (
+                        (next_frame + 1).start_address().value(),
+                        MemoryRegionKind::Usable,
+                        MemoryRegionKind::Bootloader,
+                    ),
+                };
+                push(MemoryRegion {
+                    start,
+                    len: split - start,
+                    kind: low_kind,
                 });
-
-                index += 1;
-
-                let remaining_len = (descriptor.page_count as usize * PAGE_SIZE) - used_len;
-                if remaining_len > 0 {
-                    memory_map[index].write(MemoryRegion {
-                        start: descriptor.phys_start as usize + used_len,
-                        len: remaining_len,
-                        kind: MemoryRegionKind::Usable,
+                if start + len > split {
+                    push(MemoryRegion {
+                        start: split,
+                        len: start + len - split,
+                        kind: high_kind,
                     });
-                    index += 1;
                 }
-
-                iterated_through_used_descriptors = true;
             } else {
-                memory_map[index].write(MemoryRegion {
-                    start: descriptor.phys_start as usize,
-                    len: descriptor.page_count as usize * PAGE_SIZE,
+                push(MemoryRegion {
+                    start,
+                    len,
                     kind: MemoryRegionKind::Bootloader,
                 });
-                index += 1;
             }
         }
 
         // SAFETY: We initialised all the items up to `index`.
-        unsafe { MaybeUninit::slice_assume_init_mut(&mut memory_map[..index]) }
+        let memory_map = unsafe { MaybeUninit::slice_assume_init_mut(&mut memory_map[..index]) };
+        let len = compact_memory_map(memory_map);
+        &mut memory_map[..len]
     }
 }
 
 impl FrameAllocator for LegacyFrameAllocator {
     fn allocate_frame(&mut self) -> Option<Frame> {
+        let frame = self.next_frame()?;
+        if self.zero_frames {
+            let start = crate::arch::timestamp();
+            // SAFETY: The frame is unused and UEFI's identity mapping is still active.
+            unsafe {
+                core::ptr::write_bytes(frame.start_address().value() as *mut u8, 0, PAGE_SIZE);
+            };
+            if let (Some(start), Some(end)) = (start, crate::arch::timestamp()) {
+                self.zeroing_ticks += end.saturating_sub(start);
+            }
+            self.zeroed_frames += 1;
+        }
+        Some(frame)
+    }
+}
+
+impl LegacyFrameAllocator {
+    fn next_frame(&mut self) -> Option<Frame> {
         if let Some(frame) = self.allocate_frame_from_current() {
             return Some(frame);
         }
 
-        while let Some(descriptor) = self.memory_map.next() {
-            // Allocating frames below 1MiB causes problems during AP boot.
-            if descriptor_kind(descriptor) != MemoryRegionKind::Usable
-                || descriptor.phys_start < 0x1_0000
-            {
+        while self.visited < self.descriptor_count {
+            let index = self.visited;
+            self.visited += 1;
+
+            let descriptor = self.original.clone().nth(self.reorder(index))?;
+            if !self.is_allocatable(descriptor) {
                 continue;
             }
 
-            let descriptor = CurrentDescriptor {
-                descriptor,
-                next_frame: Frame::containing_address(PhysicalAddress::new_canonical(
-                    descriptor.phys_start as usize,
-                )),
+            let next_address = match self.policy {
+                AllocPolicy::BottomUp => {
+                    PhysicalAddress::new_canonical(descriptor.phys_start as usize)
+                }
+                AllocPolicy::TopDown => self.usable_end(descriptor) - 1,
             };
-            self.current_descriptor = Some(descriptor);
+            self.current_descriptor = Some(CurrentDescriptor {
+                index,
+                descriptor,
+                next_frame: Frame::containing_address(next_address),
+            });
 
             if let Some(frame) = self.allocate_frame_from_current() {
                 return Some(frame);
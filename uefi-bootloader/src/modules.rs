@@ -1,92 +1,380 @@
-use crate::{memory::PAGE_SIZE, util::calculate_pages, BootContext};
+use crate::{config::Handoff, memory::PAGE_SIZE, multiboot2, util::calculate_pages, BootContext};
 use core::mem::MaybeUninit;
-use uefi::{
-    prelude::cstr16,
-    proto::media::file::{File, FileAttribute, FileMode},
-    table::boot::MemoryType,
-};
+use uefi::table::boot::MemoryType;
 use uefi_bootloader_api::Module;
 
-const MODULES_MEMORY: MemoryType = MemoryType::custom(0x8000_0000);
+cfg_if::cfg_if! {
+    if #[cfg(feature = "embedded-modules")] {
+        /// The modules, embedded from the directory at the path in the
+        /// `UEFI_BOOTLOADER_MODULES` environment variable at compile time.
+        ///
+        /// The modules are sorted by name.
+        static EMBEDDED_MODULES: &[(&str, &[u8])] =
+            include!(concat!(env!("OUT_DIR"), "/embedded_modules.rs"));
+    } else {
+        use crate::{
+            ext2::{Inode, Volume},
+            util::{to_uefi_path, MAX_PATH_LEN},
+        };
+        use log::warn;
+        use uefi::{
+            prelude::cstr16,
+            proto::media::file::{Directory, File, FileAttribute, FileHandle, FileInfo, FileMode},
+        };
+    }
+}
+
+pub(crate) const MODULES_MEMORY: MemoryType = MemoryType::custom(0x8000_0000);
 
 impl BootContext {
-    pub(crate) fn load_modules(&self) -> &'static mut [Module] {
-        let mut root = self
-            .open_file_system_root()
-            .expect("failed to open file system root");
+    /// Loads the modules, returning them along with the physical address of
+    /// the memory they were loaded into, which their offsets are relative to.
+    ///
+    /// The address is zero if there are no modules.
+    #[cfg(feature = "embedded-modules")]
+    pub(crate) fn load_modules(&self) -> (&'static mut [Module], usize) {
+        if EMBEDDED_MODULES.is_empty() {
+            return (&mut [], 0);
+        }
 
-        let mut dir = match root.open(cstr16!("modules"), FileMode::Read, FileAttribute::empty()) {
-            Ok(dir) => dir
-                .into_directory()
-                .expect("modules directory was closed or deleted"),
-            Err(_) => return &mut [],
+        let num_pages = EMBEDDED_MODULES
+            .iter()
+            .map(|(name, bytes)| self.module_pages(name, bytes.len()))
+            .sum::<usize>();
+
+        // This slice is copied into another slice in the bootloader, so this slice can
+        // be overwritten by the kernel.
+        let modules = self.allocate_slice(EMBEDDED_MODULES.len(), MemoryType::LOADER_DATA);
+        let raw_bytes = self.allocate_module_bytes(num_pages * PAGE_SIZE);
+
+        let mut offset = 0;
+        for ((name, bytes), module) in EMBEDDED_MODULES.iter().zip(modules.iter_mut()) {
+            let (module_offset, align) = self.align_module(raw_bytes, offset, name);
+            raw_bytes[module_offset..(module_offset + bytes.len())].copy_from_slice(bytes);
+            module.write(Module {
+                name: module_name(name.chars()),
+                offset: module_offset,
+                len: bytes.len(),
+                align,
+            });
+            // Theseus modules must not share pages i.e. the next module starts on a new
+            // page.
+            offset = module_offset + calculate_pages(bytes.len()) * PAGE_SIZE;
+        }
+
+        // SAFETY: We initialised the modules.
+        let modules = unsafe { MaybeUninit::slice_assume_init_mut(modules) };
+        (modules, raw_bytes.as_ptr() as usize)
+    }
+
+    /// Loads the modules, returning them along with the physical address of
+    /// the memory they were loaded into, which their offsets are relative to.
+    ///
+    /// The address is zero if there are no modules.
+    #[cfg(not(feature = "embedded-modules"))]
+    pub(crate) fn load_modules(&self) -> (&'static mut [Module], usize) {
+        if let Some(path) = self.config.ext2_modules_dir {
+            return self.load_ext2_modules(path);
+        }
+
+        let recursive = self.config.modules_recursive;
+
+        let mut dir = match self.open_modules_dir() {
+            Some(dir) => dir,
+            None => return (&mut [], 0),
         };
 
         let mut num_modules = 0;
         let mut num_pages = 0;
-        let mut buf = [0; 500];
 
-        while let Some(info) = dir
-            .read_entry(&mut buf)
-            .expect("failed to read modules directory entry")
-        {
-            if !info.attribute().contains(FileAttribute::DIRECTORY) {
-                num_modules += 1;
-                // Theseus modules must not share pages i.e. the next module starts on a new
-                // page.
-                num_pages += calculate_pages(info.file_size() as usize);
-            }
+        for_each_module(&mut dir, recursive, &mut |_, info| {
+            num_modules += 1;
+            let name = module_name(info.file_name().iter().map(|c16| char::from(*c16)));
+            num_pages += self.module_pages(name_str(&name), info.file_size() as usize);
+        });
+
+        if num_modules == 0 {
+            return (&mut [], 0);
         }
 
         // This slice is copied into another slice in the bootloader, so this slice can
         // be overwritten by the kernel.
         let modules = self.allocate_slice(num_modules, MemoryType::LOADER_DATA);
-        let raw_bytes = self.allocate_byte_slice(num_pages * PAGE_SIZE, MODULES_MEMORY);
+        let raw_bytes = self.allocate_module_bytes(num_pages * PAGE_SIZE);
 
         dir.reset_entry_readout()
             .expect("failed to reset modules directory entry readout");
 
         let mut idx = 0;
-        let mut num_pages = 0;
+        let mut offset = 0;
 
-        while let Some(info) = dir
-            .read_entry(&mut buf)
-            .expect("failed to read modules directory entry")
-        {
-            if !info.attribute().contains(FileAttribute::DIRECTORY) {
-                let name = info.file_name();
-
-                let len = info.file_size() as usize;
-                let mut file = dir
-                    .open(info.file_name(), FileMode::Read, FileAttribute::empty())
-                    .expect("failed to open module")
-                    .into_regular_file()
-                    .expect("module file was closed or deleted");
-
-                file.read(&mut raw_bytes[(num_pages * 4096)..])
-                    .expect("failed to read module");
-
-                let mut name_buf = [0; 64];
-                let mut name_idx = 0;
-                for c16 in name.iter() {
-                    let c = char::from(*c16);
-                    let s = c.encode_utf8(&mut name_buf[name_idx..(name_idx + 4)]);
-                    name_idx += s.len();
-                }
-
-                modules[idx].write(Module {
-                    name: name_buf,
-                    offset: num_pages * 4096,
-                    len,
+        for_each_module(&mut dir, recursive, &mut |dir, info| {
+            let len = info.file_size() as usize;
+            let name = module_name(info.file_name().iter().map(|c16| char::from(*c16)));
+            let (module_offset, align) = self.align_module(raw_bytes, offset, name_str(&name));
+
+            let result = dir
+                .open(info.file_name(), FileMode::Read, FileAttribute::empty())
+                .ok()
+                .and_then(FileHandle::into_regular_file)
+                .ok_or("failed to open module")
+                .and_then(|mut file| {
+                    file.read(&mut raw_bytes[module_offset..])
+                        .map_err(|_| "failed to read module")
                 });
+            if let Err(reason) = check_module_len(result, len) {
+                self.skip_module(name_str(&name), reason);
+                return;
+            }
+
+            modules[idx].write(Module {
+                name,
+                offset: module_offset,
+                len,
+                align,
+            });
+
+            idx += 1;
+            // Theseus modules must not share pages i.e. the next module starts on a new
+            // page.
+            offset = module_offset + calculate_pages(len) * PAGE_SIZE;
+        });
+
+        // Modules that failed to load were skipped, so fewer modules may have been
+        // initialised.
+        // SAFETY: We just initialised the first `idx` modules.
+        let modules = unsafe { MaybeUninit::slice_assume_init_mut(&mut modules[..idx]) };
+        // Directory entries aren't returned in any particular order.
+        modules.sort_unstable_by(|a, b| a.name().cmp(b.name()));
+        (modules, raw_bytes.as_ptr() as usize)
+    }
+
+    #[cfg(not(feature = "embedded-modules"))]
+    fn load_ext2_modules(&self, path: &str) -> (&'static mut [Module], usize) {
+        let recursive = self.config.modules_recursive;
+        let volume = match self.open_ext2_volume() {
+            Ok(volume) => volume,
+            Err(reason) => {
+                self.skip_module(path, reason);
+                return (&mut [], 0);
+            }
+        };
+
+        let dir = match volume.open(path) {
+            Ok(Some(dir)) if dir.is_dir() => dir,
+            Ok(_) => return (&mut [], 0),
+            Err(reason) => {
+                self.skip_module(path, reason);
+                return (&mut [], 0);
+            }
+        };
+
+        let mut num_modules = 0;
+        let mut num_pages = 0;
+
+        let result = for_each_ext2_module(&volume, &dir, recursive, &mut |name, inode| {
+            num_modules += 1;
+            num_pages += self.module_pages(name, inode.size());
+        });
+        if let Err(reason) = result {
+            self.skip_module(path, reason);
+            return (&mut [], 0);
+        }
+
+        if num_modules == 0 {
+            return (&mut [], 0);
+        }
 
-                idx += 1;
-                num_pages += calculate_pages(len);
+        // This slice is copied into another slice in the bootloader, so this slice can
+        // be overwritten by the kernel.
+        let modules = self.allocate_slice(num_modules, MemoryType::LOADER_DATA);
+        let raw_bytes = self.allocate_module_bytes(num_pages * PAGE_SIZE);
+
+        let mut idx = 0;
+        let mut offset = 0;
+
+        let result = for_each_ext2_module(&volume, &dir, recursive, &mut |name, inode| {
+            // The directory could have changed since the modules were counted.
+            if idx == modules.len() {
+                return;
             }
+            let len = inode.size();
+            let (module_offset, align) = self.align_module(raw_bytes, offset, name);
+
+            let result = volume.read(inode, 0, &mut raw_bytes[module_offset..]);
+            if let Err(reason) = check_module_len(result, len) {
+                self.skip_module(name, reason);
+                return;
+            }
+
+            modules[idx].write(Module {
+                name: module_name(name.chars()),
+                offset: module_offset,
+                len,
+                align,
+            });
+
+            idx += 1;
+            // Theseus modules must not share pages i.e. the next module starts on a new
+            // page.
+            offset = module_offset + calculate_pages(len) * PAGE_SIZE;
+        });
+        if let Err(reason) = result {
+            self.skip_module(path, reason);
+        }
+
+        // Modules that failed to load were skipped, so fewer modules may have been
+        // initialised.
+        // SAFETY: We just initialised the first `idx` modules.
+        let modules = unsafe { MaybeUninit::slice_assume_init_mut(&mut modules[..idx]) };
+        // Directory entries aren't returned in any particular order.
+        modules.sort_unstable_by(|a, b| a.name().cmp(b.name()));
+        (modules, raw_bytes.as_ptr() as usize)
+    }
+
+    /// Panics with the reason a module failed to load, unless
+    /// `modules_optional` is set, in which case the module is skipped.
+    ///
+    /// `name` is the modules directory if none of its remaining modules could
+    /// be loaded.
+    #[cfg(not(feature = "embedded-modules"))]
+    fn skip_module(&self, name: &str, reason: &str) {
+        assert!(
+            self.config.modules_optional,
+            "failed to load module {name}: {reason}"
+        );
+        warn!("skipping module {name}: {reason}");
+    }
+
+    /// Allocates the memory that the modules are loaded into.
+    ///
+    /// Multiboot2 module tags hold 32-bit addresses, so the modules are kept
+    /// below 4 GiB when using that handoff.
+    fn allocate_module_bytes(&self, len: usize) -> &'static mut [u8] {
+        match self.config.handoff {
+            Handoff::Native => self.allocate_byte_slice(len, MODULES_MEMORY),
+            Handoff::Multiboot2Info => {
+                self.allocate_byte_slice_below(len, MODULES_MEMORY, multiboot2::MAX_ADDRESS)
+            }
+        }
+    }
+
+    /// Returns the number of pages needed to load the module, including any
+    /// padding needed to align it.
+    fn module_pages(&self, name: &str, len: usize) -> usize {
+        let align = self.config.module_aligns.get(name);
+        // In the worst case, the module starts one page after an aligned address.
+        calculate_pages(len) + (align / PAGE_SIZE) - 1
+    }
+
+    /// Returns the offset of the first address at or after `offset` in
+    /// `raw_bytes` that meets the module's configured alignment, along with
+    /// the alignment of that address.
+    fn align_module(&self, raw_bytes: &[u8], offset: usize, name: &str) -> (usize, usize) {
+        let align = self.config.module_aligns.get(name);
+        let base = raw_bytes.as_ptr() as usize;
+        let address = (base + offset + align - 1) & !(align - 1);
+        // The achieved alignment may be larger than requested.
+        (address - base, 1 << address.trailing_zeros())
+    }
+
+    #[cfg(not(feature = "embedded-modules"))]
+    fn open_modules_dir(&self) -> Option<Directory> {
+        let mut path_buf = [0; MAX_PATH_LEN];
+        let path = to_uefi_path(self.config.modules_dir, &mut path_buf);
+
+        let mut root = self
+            .open_file_system_root()
+            .expect("failed to open file system root");
+        let dir = root
+            .open(path, FileMode::Read, FileAttribute::empty())
+            .ok()?;
+        Some(
+            dir.into_directory()
+                .expect("modules directory was closed or deleted"),
+        )
+    }
+}
+
+/// Calls `f` with every regular file in `dir`, descending into subdirectories
+/// if `recursive` is set.
+#[cfg(not(feature = "embedded-modules"))]
+fn for_each_module(
+    dir: &mut Directory,
+    recursive: bool,
+    f: &mut dyn FnMut(&mut Directory, &FileInfo),
+) {
+    let mut buf = [0; 500];
+
+    while let Some(info) = dir
+        .read_entry(&mut buf)
+        .expect("failed to read modules directory entry")
+    {
+        if !info.attribute().contains(FileAttribute::DIRECTORY) {
+            f(dir, info);
+        } else if recursive && info.file_name() != cstr16!(".") && info.file_name() != cstr16!("..")
+        {
+            let mut subdir = dir
+                .open(info.file_name(), FileMode::Read, FileAttribute::empty())
+                .expect("failed to open modules subdirectory")
+                .into_directory()
+                .expect("modules subdirectory was closed or deleted");
+            for_each_module(&mut subdir, recursive, f);
         }
+    }
+}
+
+/// Calls `f` with every regular file in `dir` on an ext2 partition,
+/// descending into subdirectories if `recursive` is set.
+///
+/// Returns an error if a directory couldn't be read.
+#[cfg(not(feature = "embedded-modules"))]
+fn for_each_ext2_module(
+    volume: &Volume<'_>,
+    dir: &Inode,
+    recursive: bool,
+    f: &mut dyn FnMut(&str, &Inode),
+) -> Result<(), &'static str> {
+    volume.for_each_entry(dir, |name, inode| {
+        if inode.is_regular() {
+            f(name, &inode);
+        } else if recursive && inode.is_dir() {
+            for_each_ext2_module(volume, &inode, recursive, f)?;
+        }
+        Ok(())
+    })
+}
 
-        assert_eq!(idx, modules.len());
-        // SAFETY: We just initialised the slice and checked that it's the same length.
-        unsafe { MaybeUninit::slice_assume_init_mut(modules) }
+/// Encodes the name as null-padded UTF-8.
+fn module_name(name: impl Iterator<Item = char>) -> [u8; 64] {
+    let mut name_buf = [0; 64];
+    let mut name_idx = 0;
+    for c in name {
+        // Names that don't fit are truncated, leaving at least one null byte.
+        if name_idx + c.len_utf8() >= name_buf.len() {
+            break;
+        }
+        let s = c.encode_utf8(&mut name_buf[name_idx..]);
+        name_idx += s.len();
     }
+    name_buf
+}
+
+/// Checks that the whole module was read.
+#[cfg(not(feature = "embedded-modules"))]
+fn check_module_len(read: Result<usize, &'static str>, len: usize) -> Result<(), &'static str> {
+    match read? {
+        read if read == len => Ok(()),
+        _ => Err("module is truncated"),
+    }
+}
+
+/// Returns the module name without the trailing null bytes.
+#[cfg(not(feature = "embedded-modules"))]
+fn name_str(name: &[u8; 64]) -> &str {
+    let end = name
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(name.len());
+    core::str::from_utf8(&name[..end]).expect("invalid bytes in module name")
 }
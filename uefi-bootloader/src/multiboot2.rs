@@ -0,0 +1,314 @@
+//! Support for passing a Multiboot2 information structure to the kernel.
+//!
+//! See the [Multiboot2 specification] for the layout of the structure. The
+//! kernel is entered using the bootloader's own 64-bit convention rather than
+//! the specification's machine state, see
+//! [`Handoff::Multiboot2Info`](crate::config::Handoff::Multiboot2Info).
+//!
+//! [Multiboot2 specification]: https://www.gnu.org/software/grub/manual/multiboot2/multiboot.html
+
+use crate::{
+    context::RuntimeContext,
+    memory::{Frame, FrameRange, Page, PageRange, PhysicalAddress, PteFlags, VirtualAddress},
+    rsdp_bytes,
+    selftest::Target,
+    util::calculate_pages,
+};
+use core::{alloc::Layout, mem::MaybeUninit, slice};
+use log::warn;
+use uefi_bootloader_api::{FrameBuffer, MemoryRegion, MemoryRegionKind, Module, PixelFormat};
+
+/// The value passed to the kernel to indicate that it was loaded by a
+/// Multiboot2-compliant bootloader.
+pub(crate) const BOOTLOADER_MAGIC: u32 = 0x36d7_6289;
+
+/// The address below which the information structure and modules must be
+/// placed, as the kernel receives 32-bit addresses.
+pub(crate) const MAX_ADDRESS: usize = 1 << 32;
+
+const BOOTLOADER_NAME: &str = "uefi-bootloader";
+
+const TAG_END: u32 = 0;
+const TAG_CMDLINE: u32 = 1;
+const TAG_BOOTLOADER_NAME: u32 = 2;
+const TAG_MODULE: u32 = 3;
+const TAG_MEMORY_MAP: u32 = 6;
+const TAG_FRAME_BUFFER: u32 = 8;
+const TAG_ACPI_OLD: u32 = 14;
+const TAG_ACPI_NEW: u32 = 15;
+
+const MEMORY_MAP_ENTRY_SIZE: usize = 24;
+
+const MEMORY_AVAILABLE: u32 = 1;
+const MEMORY_RESERVED: u32 = 2;
+const MEMORY_ACPI_RECLAIMABLE: u32 = 3;
+const MEMORY_NVS: u32 = 4;
+
+const FRAME_BUFFER_TYPE_RGB: u8 = 1;
+
+impl RuntimeContext {
+    /// Creates the Multiboot2 information structure, returning its physical
+    /// address.
+    ///
+    /// The structure, and the modules it references, are identity-mapped in
+    /// the kernel's page table as the structure only contains physical
+    /// addresses.
+    pub(crate) fn create_multiboot2_info(
+        &mut self,
+        frame_buffer: Option<FrameBuffer>,
+        rsdp_address: Option<usize>,
+        modules: &'static [Module],
+        modules_address: usize,
+    ) -> PhysicalAddress {
+        let info = Info {
+            cmdline: self.config.cmdline,
+            frame_buffer,
+            rsdp: rsdp_address.and_then(|address| {
+                // SAFETY: We haven't switched page tables, so UEFI's identity mapping is
+                // still active.
+                let bytes = unsafe { rsdp_bytes(address) };
+                if bytes.is_none() {
+                    warn!(
+                        "RSDP at {address:#x} has an invalid length, not passing it to the kernel"
+                    );
+                }
+                bytes
+            }),
+            modules,
+            modules_address,
+        };
+
+        let memory_regions_count = self.frame_allocator.len();
+        let info_layout = Layout::from_size_align(info.size(memory_regions_count), 8)
+            .expect("failed to create multiboot2 information layout");
+        let memory_regions_layout = Layout::array::<MemoryRegion>(memory_regions_count)
+            .expect("failed to create memory regions layout");
+        let (combined, memory_regions_offset) = info_layout
+            .extend(memory_regions_layout)
+            .expect("failed to extend multiboot2 information layout with memory regions");
+
+        let frames = self
+            .frame_allocator
+            .allocate_contiguous_frames(calculate_pages(combined.size()))
+            .expect("failed to allocate multiboot2 information frames");
+        let address = frames.start_address();
+        // Top-down allocation is rejected when parsing the configuration, so this
+        // only fails if there is no usable memory below 4 GiB.
+        assert!(
+            address.value() + combined.size() <= MAX_ADDRESS,
+            "multiboot2 information must be below 4 GiB"
+        );
+
+        let flags = PteFlags::new()
+            .present(true)
+            .writable(true)
+            .no_execute(true);
+        self.identity_map_frames("multiboot2 information", frames, flags);
+        for module in modules {
+            let start = PhysicalAddress::new_canonical(modules_address + module.offset);
+            let frames = FrameRange::from_phys_addr(start, module.len.max(1));
+            self.identity_map_frames("module", frames, flags);
+        }
+
+        // SAFETY: We allocated the frames and UEFI's identity mapping is still active.
+        let buffer =
+            unsafe { slice::from_raw_parts_mut(address.value() as *mut u8, combined.size()) };
+        let (info_buffer, memory_regions_buffer) = buffer.split_at_mut(memory_regions_offset);
+        // SAFETY: The layout guarantees the buffer is correctly sized and aligned.
+        let uninit_memory_regions: &mut [MaybeUninit<MemoryRegion>] = unsafe {
+            slice::from_raw_parts_mut(
+                memory_regions_buffer.as_mut_ptr().cast(),
+                memory_regions_count,
+            )
+        };
+
+        // No more frames can be allocated after constructing the memory map.
+        let memory_regions = self
+            .frame_allocator
+            .construct_memory_map(uninit_memory_regions);
+
+        info.write(&mut Writer::new(Some(info_buffer)), memory_regions);
+
+        address
+    }
+
+    fn identity_map_frames(&mut self, name: &'static str, frames: FrameRange, flags: PteFlags) {
+        let page = |frame: &Frame| {
+            Page::containing_address(VirtualAddress::new_canonical(frame.start_address().value()))
+        };
+        self.mappings.record(
+            name,
+            PageRange::new(page(frames.start()), page(frames.end())),
+            Target::Frames(*frames.start()),
+            flags,
+        );
+
+        for frame in frames {
+            self.mapper
+                .identity_map(frame, flags, &mut self.frame_allocator);
+        }
+    }
+}
+
+struct Info {
+    cmdline: &'static str,
+    frame_buffer: Option<FrameBuffer>,
+    rsdp: Option<&'static [u8]>,
+    modules: &'static [Module],
+    modules_address: usize,
+}
+
+impl Info {
+    /// Returns the size of the information structure if the memory map has
+    /// `memory_regions_count` regions.
+    fn size(&self, memory_regions_count: usize) -> usize {
+        let mut writer = Writer::new(None);
+        self.write(&mut writer, &[]);
+        // The memory map entries are a multiple of the tag alignment, so they don't
+        // affect the padding.
+        writer.len + memory_regions_count * MEMORY_MAP_ENTRY_SIZE
+    }
+
+    fn write(&self, writer: &mut Writer<'_>, memory_regions: &[MemoryRegion]) {
+        // The total size is patched in once all the tags are written.
+        writer.u32(0);
+        writer.u32(0);
+
+        writer.tag(TAG_CMDLINE, |writer| writer.string(self.cmdline));
+        writer.tag(TAG_BOOTLOADER_NAME, |writer| writer.string(BOOTLOADER_NAME));
+
+        for module in self.modules {
+            let start = self.modules_address + module.offset;
+            writer.tag(TAG_MODULE, |writer| {
+                writer.u32(to_u32(start, "module start"));
+                writer.u32(to_u32(start + module.len, "module end"));
+                writer.string(module.name());
+            });
+        }
+
+        writer.tag(TAG_MEMORY_MAP, |writer| {
+            writer.u32(MEMORY_MAP_ENTRY_SIZE as u32);
+            // The entry version.
+            writer.u32(0);
+            for region in memory_regions {
+                writer.u64(region.start as u64);
+                writer.u64(region.len as u64);
+                writer.u32(memory_type(region.kind));
+                writer.u32(0);
+            }
+        });
+
+        if let Some(frame_buffer) = self.frame_buffer {
+            let info = frame_buffer.info;
+            writer.tag(TAG_FRAME_BUFFER, |writer| {
+                writer.u64(frame_buffer.physical as u64);
+                writer.u32(to_u32(info.pitch_bytes, "frame buffer pitch"));
+                writer.u32(to_u32(info.width, "frame buffer width"));
+                writer.u32(to_u32(info.height, "frame buffer height"));
+                writer.u8((info.bytes_per_pixel * 8) as u8);
+                writer.u8(FRAME_BUFFER_TYPE_RGB);
+                writer.u16(0);
+
+                let (red, blue) = match info.pixel_format {
+                    PixelFormat::Rgb => (0, 16),
+                    PixelFormat::Bgr => (16, 0),
+                };
+                // The position and size of the red, green, and blue fields.
+                writer.bytes(&[red, 8, 8, 8, blue, 8]);
+            });
+        }
+
+        if let Some(rsdp) = self.rsdp {
+            let ty = if rsdp[15] < 2 {
+                TAG_ACPI_OLD
+            } else {
+                TAG_ACPI_NEW
+            };
+            writer.tag(ty, |writer| writer.bytes(rsdp));
+        }
+
+        writer.tag(TAG_END, |_| {});
+
+        let total_size = to_u32(writer.len, "multiboot2 information size");
+        writer.patch_u32(0, total_size);
+    }
+}
+
+fn memory_type(kind: MemoryRegionKind) -> u32 {
+    match kind {
+        MemoryRegionKind::Usable => MEMORY_AVAILABLE,
+        MemoryRegionKind::AcpiReclaimable => MEMORY_ACPI_RECLAIMABLE,
+        MemoryRegionKind::AcpiNvs => MEMORY_NVS,
+        _ => MEMORY_RESERVED,
+    }
+}
+
+fn to_u32(value: usize, name: &str) -> u32 {
+    u32::try_from(value).unwrap_or_else(|_| panic!("{name} doesn't fit in 32 bits: {value:#x}"))
+}
+
+/// Writes the information structure into a buffer.
+///
+/// If there is no buffer, the writer only measures the size of the structure.
+struct Writer<'a> {
+    buffer: Option<&'a mut [u8]>,
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn new(buffer: Option<&'a mut [u8]>) -> Self {
+        Self { buffer, len: 0 }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        if let Some(buffer) = self.buffer.as_mut() {
+            buffer[self.len..(self.len + bytes.len())].copy_from_slice(bytes);
+        }
+        self.len += bytes.len();
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.bytes(&[value]);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    /// Writes a null-terminated string.
+    fn string(&mut self, value: &str) {
+        self.bytes(value.as_bytes());
+        self.u8(0);
+    }
+
+    fn patch_u32(&mut self, offset: usize, value: u32) {
+        if let Some(buffer) = self.buffer.as_mut() {
+            buffer[offset..(offset + 4)].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    /// Writes a tag with the given type, whose contents are written by `f`.
+    fn tag(&mut self, ty: u32, f: impl FnOnce(&mut Self)) {
+        // Tags are 8-byte aligned.
+        while self.len % 8 != 0 {
+            self.u8(0);
+        }
+
+        let start = self.len;
+        self.u32(ty);
+        // The size is patched in once the contents are written.
+        self.u32(0);
+        f(self);
+
+        let size = to_u32(self.len - start, "multiboot2 tag size");
+        self.patch_u32(start + 4, size);
+    }
+}
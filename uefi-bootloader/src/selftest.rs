@@ -0,0 +1,270 @@
+use crate::{
+    config::AllocPolicy,
+    memory::{
+        Frame, FrameAllocator, Page, PageRange, PhysicalAddress, PteFlags, UefiFrameAllocator,
+    },
+    util::calculate_pages,
+    BootContext, RuntimeContext,
+};
+use core::{mem::MaybeUninit, slice};
+use log::{error, info, warn};
+use uefi_bootloader_api::{MemoryRegion, MemoryRegionKind};
+
+/// The maximum number of mappings that can be recorded.
+const MAX_MAPPINGS: usize = 64;
+
+/// What a recorded page is expected to be mapped to.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Target {
+    /// The pages are mapped to contiguous frames starting at the given frame.
+    Frames(Frame),
+    /// The pages are mapped to frames that aren't necessarily contiguous.
+    Any,
+    /// The pages aren't mapped, e.g. guard pages.
+    Unmapped,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Mapping {
+    name: &'static str,
+    start: Page,
+    len: usize,
+    target: Target,
+    flags: PteFlags,
+}
+
+/// The mappings created in the kernel's page table, used to check the page
+/// table before jumping to the kernel.
+pub(crate) struct Mappings {
+    entries: [Option<Mapping>; MAX_MAPPINGS],
+    len: usize,
+}
+
+impl Mappings {
+    pub(crate) const fn new() -> Self {
+        Self {
+            entries: [None; MAX_MAPPINGS],
+            len: 0,
+        }
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        name: &'static str,
+        pages: PageRange,
+        target: Target,
+        flags: PteFlags,
+    ) {
+        if self.len == MAX_MAPPINGS {
+            warn!("not recording {name} mapping: too many mappings");
+            return;
+        }
+        self.entries[self.len] = Some(Mapping {
+            name,
+            start: *pages.start(),
+            len: pages.size_in_pages(),
+            target,
+            flags,
+        });
+        self.len += 1;
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Mapping> {
+        self.entries[..self.len].iter().flatten()
+    }
+}
+
+/// The limit used to check constrained frame allocation, below which AP
+/// trampolines must be placed.
+const LOW_MEMORY_LIMIT: usize = 0x10_0000;
+
+impl BootContext {
+    /// Checks that frames can be allocated below 1 MiB, logging the result.
+    ///
+    /// The frames are freed afterwards. Returns whether the allocation
+    /// succeeded and respected the limit.
+    pub(crate) fn self_test_allocation(&self) -> bool {
+        const COUNT: usize = 2;
+
+        let limit = PhysicalAddress::new_canonical(LOW_MEMORY_LIMIT);
+        let frames = UefiFrameAllocator {
+            system_table: &self.system_table,
+            max_address: None,
+        }
+        .allocate_frames_in_range(COUNT, limit);
+
+        let frames = match frames {
+            Some(frames) => frames,
+            None => {
+                error!("allocation self-test FAIL: no {COUNT} contiguous frames below {limit}");
+                return false;
+            }
+        };
+        let is_correct = frames.size_in_frames() == COUNT && frames.end().start_address() < limit;
+
+        let start = frames.start().start_address().value() as u64;
+        if let Err(error) = self.system_table.boot_services().free_pages(start, COUNT) {
+            warn!("failed to free self-test frames: {:?}", error.status());
+        }
+
+        if is_correct {
+            info!("allocation self-test PASS: allocated {frames:?} below {limit}");
+        } else {
+            error!(
+                "allocation self-test FAIL: allocated {frames:?}, expected frames below {limit}"
+            );
+        }
+        is_correct
+    }
+}
+
+impl RuntimeContext {
+    /// Checks that every recorded mapping resolves to the expected frames with
+    /// the expected flags, logging a summary.
+    ///
+    /// Returns whether all the mappings are correct.
+    pub(crate) fn self_test(&self) -> bool {
+        let mut num_pages = 0;
+        let mut num_failures = 0;
+
+        for mapping in self.mappings.iter() {
+            for i in 0..mapping.len {
+                let page = mapping.start + i;
+                let actual = self.mapper.translate(page);
+
+                let is_correct = match (mapping.target, actual) {
+                    (Target::Unmapped, None) => true,
+                    (Target::Unmapped, Some(_)) | (_, None) => false,
+                    (Target::Any, Some((_, flags))) => {
+                        flags.permissions() == mapping.flags.permissions()
+                    }
+                    (Target::Frames(start), Some((frame, flags))) => {
+                        frame == start + i && flags.permissions() == mapping.flags.permissions()
+                    }
+                };
+
+                num_pages += 1;
+                if !is_correct {
+                    num_failures += 1;
+                    error!(
+                        "FAIL: {} {page:?}: expected {:?} with {:?}, got {actual:?}",
+                        mapping.name, mapping.target, mapping.flags
+                    );
+                }
+            }
+        }
+
+        if num_failures == 0 {
+            info!(
+                "self-test PASS: checked {num_pages} pages in {} mappings",
+                self.mappings.len
+            );
+        } else {
+            error!("self-test FAIL: {num_failures} of {num_pages} pages are incorrectly mapped");
+        }
+
+        num_failures == 0
+    }
+    /// Checks that frames are allocated in the direction of the allocation
+    /// policy, and that the memory map reports the allocated frames at the
+    /// correct end of their descriptor, logging the result.
+    ///
+    /// This allocates frames after the memory map in the boot information was
+    /// constructed, so it must only be run when halting rather than jumping
+    /// to the kernel. Returns whether the allocations were correct.
+    pub(crate) fn self_test_alloc_policy(&mut self) -> bool {
+        const COUNT: usize = 4;
+
+        let policy = self.config.alloc_policy;
+        let regions_count = self.frame_allocator.len();
+        let buffer = self
+            .frame_allocator
+            .allocate_contiguous_frames(calculate_pages(
+                regions_count * core::mem::size_of::<MemoryRegion>(),
+            ))
+            .expect("failed to allocate self-test memory map frames");
+
+        let mut is_correct = true;
+        let mut last = self
+            .frame_allocator
+            .allocate_frame()
+            .expect("failed to allocate self-test frame");
+        for _ in 1..COUNT {
+            let frame = self
+                .frame_allocator
+                .allocate_frame()
+                .expect("failed to allocate self-test frame");
+            let is_ordered = match policy {
+                AllocPolicy::BottomUp => frame > last,
+                AllocPolicy::TopDown => frame < last,
+            };
+            if !is_ordered {
+                error!("FAIL: allocated {frame:?} after {last:?} with the {policy:?} policy");
+                is_correct = false;
+            }
+            last = frame;
+        }
+
+        let next = self.frame_allocator.peek_frame();
+        // SAFETY: We allocated the frames and UEFI's identity mapping is still active.
+        let uninit_memory_regions: &mut [MaybeUninit<MemoryRegion>] = unsafe {
+            slice::from_raw_parts_mut(buffer.start_address().value() as *mut _, regions_count)
+        };
+        let memory_regions = self
+            .frame_allocator
+            .construct_memory_map(uninit_memory_regions);
+        let region = |frame: Frame| {
+            let address = frame.start_address().value();
+            memory_regions
+                .iter()
+                .find(|region| (region.start..(region.start + region.len)).contains(&address))
+        };
+
+        let allocated = region(last);
+        if allocated.map(|region| region.kind) != Some(MemoryRegionKind::Bootloader) {
+            error!("FAIL: {last:?} is reported as {allocated:?}, expected bootloader memory");
+            is_correct = false;
+        }
+
+        // If the descriptor isn't exhausted, its allocated and free parts meet between
+        // the last allocated frame and the next one.
+        if let Some(next) = next {
+            let free = region(next);
+            let boundary = match policy {
+                AllocPolicy::BottomUp => next,
+                AllocPolicy::TopDown => last,
+            }
+            .start_address()
+            .value();
+            let is_split_correct = match (allocated, free) {
+                (Some(allocated), Some(free)) => {
+                    free.kind == MemoryRegionKind::Usable
+                        && match policy {
+                            AllocPolicy::BottomUp => {
+                                allocated.start + allocated.len == boundary
+                                    && free.start == boundary
+                            }
+                            AllocPolicy::TopDown => {
+                                free.start + free.len == boundary && allocated.start == boundary
+                            }
+                        }
+                }
+                _ => false,
+            };
+            if !is_split_correct {
+                error!(
+                    "FAIL: expected {allocated:?} to border usable memory {free:?} at \
+                     {boundary:#x}"
+                );
+                is_correct = false;
+            }
+        }
+
+        if is_correct {
+            info!("allocation policy self-test PASS: {policy:?} allocations ended at {last:?}");
+        } else {
+            error!("allocation policy self-test FAIL: {policy:?} allocations are misplaced");
+        }
+        is_correct
+    }
+}
@@ -0,0 +1,43 @@
+use core::cmp::min;
+use uefi::proto::media::file::{File, RegularFile};
+
+/// A source of bytes, such as the kernel or a module.
+pub(crate) trait Source {
+    /// Returns the size of the source in bytes.
+    fn size(&mut self) -> uefi::Result<u64>;
+
+    /// Reads bytes starting at `offset` into `buffer`, returning the number of
+    /// bytes read.
+    ///
+    /// Fewer bytes than the length of `buffer` are read if the end of the
+    /// source is reached.
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> uefi::Result<usize>;
+}
+
+impl Source for RegularFile {
+    fn size(&mut self) -> uefi::Result<u64> {
+        self.set_position(RegularFile::END_OF_FILE)?;
+        self.get_position()
+    }
+
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> uefi::Result<usize> {
+        self.set_position(offset)?;
+        self.read(buffer).map_err(|error| error.status().into())
+    }
+}
+
+impl Source for &[u8] {
+    fn size(&mut self) -> uefi::Result<u64> {
+        Ok(<[u8]>::len(self) as u64)
+    }
+
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> uefi::Result<usize> {
+        let remaining = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| self.get(offset..))
+            .unwrap_or(&[]);
+        let len = min(remaining.len(), buffer.len());
+        buffer[..len].copy_from_slice(&remaining[..len]);
+        Ok(len)
+    }
+}
@@ -0,0 +1,95 @@
+//! Support for the legacy Universal Graphics Adapter (UGA) protocol.
+//!
+//! Older firmwares, notably those of older Macs, provide the UGA draw protocol
+//! instead of the graphics output protocol. Unlike the graphics output
+//! protocol, it doesn't report the address of the frame buffer, so the address
+//! is read from the PCI BARs of the display controller. It is assumed that the
+//! frame buffer starts at the beginning of the first prefetchable BAR and that
+//! there is no padding between lines, which holds for the firmwares that only
+//! provide UGA. If the address can't be determined, no frame buffer is used
+//! and logs are only written to the console.
+
+use crate::{arch, firmware};
+use log::warn;
+use uefi::{
+    proto::unsafe_protocol,
+    table::{Boot, SystemTable},
+    Handle, Status,
+};
+use uefi_bootloader_api::{FrameBuffer, FrameBufferInfo, PixelFormat};
+
+/// The UGA draw protocol.
+#[repr(C)]
+#[unsafe_protocol("982c298b-f4fa-41cb-b838-77aa688fb839")]
+struct UgaDraw {
+    get_mode: extern "efiapi" fn(
+        this: &UgaDraw,
+        horizontal_resolution: &mut u32,
+        vertical_resolution: &mut u32,
+        color_depth: &mut u32,
+        refresh_rate: &mut u32,
+    ) -> Status,
+    set_mode: usize,
+    blt: usize,
+}
+
+impl UgaDraw {
+    /// Returns the width, height, and color depth of the current mode.
+    fn mode(&self) -> uefi::Result<(usize, usize, u32)> {
+        let mut width = 0;
+        let mut height = 0;
+        let mut color_depth = 0;
+        let mut refresh_rate = 0;
+        (self.get_mode)(
+            self,
+            &mut width,
+            &mut height,
+            &mut color_depth,
+            &mut refresh_rate,
+        )
+        .into_with_val(|| (width as usize, height as usize, color_depth))
+    }
+}
+
+pub(crate) fn get_frame_buffer(
+    image_handle: Handle,
+    system_table: &SystemTable<Boot>,
+) -> Option<FrameBuffer> {
+    let handle = firmware::find_handle::<UgaDraw>(system_table)?;
+    let uga = firmware::open_protocol::<UgaDraw>(system_table, handle, image_handle)?;
+
+    let (width, height, color_depth) = match uga.mode() {
+        Ok(mode) => mode,
+        Err(error) => {
+            warn!("failed to get UGA mode: {:?}", error.status());
+            return None;
+        }
+    };
+    // UGA pixels are always 32 bits, but some firmwares report a smaller depth.
+    if color_depth != 32 {
+        warn!("UGA color depth is {color_depth}, assuming 32 bits per pixel");
+    }
+
+    let physical = match arch::display_controller_bar() {
+        Some(address) => address,
+        None => {
+            warn!("failed to determine the UGA frame buffer address");
+            return None;
+        }
+    };
+
+    Some(FrameBuffer {
+        physical,
+        virt: 0,
+        info: FrameBufferInfo {
+            size: width * height * 4,
+            width,
+            height,
+            // UGA pixels are laid out as blue, green, red, reserved.
+            pixel_format: PixelFormat::Bgr,
+            bytes_per_pixel: 4,
+            stride: width,
+            pitch_bytes: width * 4,
+        },
+    })
+}
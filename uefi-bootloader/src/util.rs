@@ -1,3 +1,65 @@
+use uefi::CStr16;
+
+/// The maximum length of a path on a UEFI file system in UCS-2 characters,
+/// including the null terminator.
+pub(crate) const MAX_PATH_LEN: usize = 256;
+
 pub(crate) fn calculate_pages(bytes: usize) -> usize {
     ((bytes - 1) / 4096) + 1
 }
+
+/// Returns the CRC-32 (IEEE 802.3) of the bytes.
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    const POLYNOMIAL: u32 = 0xedb8_8320;
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ POLYNOMIAL
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+
+    !bytes.iter().fold(!0, |crc, byte| {
+        TABLE[((crc ^ u32::from(*byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Converts a UTF-8 path from the configuration to a UCS-2 path.
+///
+/// Forward slashes are converted to backslashes, the UEFI path separator.
+/// Panics if the path contains a null character or a character outside the
+/// basic multilingual plane, neither of which can be represented, or if the
+/// path is too long.
+pub(crate) fn to_uefi_path<'a>(path: &str, buf: &'a mut [u16; MAX_PATH_LEN]) -> &'a CStr16 {
+    let mut len = 0;
+    for c in path.chars() {
+        let c = if c == '/' { '\\' } else { c };
+        let c16 = u16::try_from(u32::from(c))
+            .ok()
+            .filter(|c16| *c16 != 0)
+            .unwrap_or_else(|| {
+                panic!("path {path:?} contains {c:?}, which can't be represented in UCS-2")
+            });
+        assert!(
+            len < MAX_PATH_LEN - 1,
+            "path {path:?} is longer than {} characters",
+            MAX_PATH_LEN - 1
+        );
+        buf[len] = c16;
+        len += 1;
+    }
+    buf[len] = 0;
+    CStr16::from_u16_with_nul(&buf[..=len]).expect("failed to convert path to UCS-2")
+}